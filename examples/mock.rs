@@ -1,4 +1,4 @@
-use std::{collections::HashMap, time::Duration};
+use std::{collections::HashMap, env, time::Duration};
 
 use generic_device_plugin::{
     ContainerAllocateResponse, ContainerPreferredAllocationResponse, Device, DeviceSpec,
@@ -11,17 +11,17 @@ use tokio::{
 use tonic::Status;
 use tracing::info;
 
-static DEVICE_PLUGIN_PATH: &str = "/var/lib/kubelet/device-plugins/";
 static DEVICE_PLUGIN_SOCK: &str = "mock.sock";
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
 
-    let server = GenericDevicePluginServer::<MockDevicePlugin>::new(
-        DEVICE_PLUGIN_PATH.into(),
-        DEVICE_PLUGIN_SOCK.to_string(),
-    );
+    let mut server = GenericDevicePluginServer::<MockDevicePlugin>::from_env();
+    if env::var_os("DEVICE_PLUGIN_SOCK").is_none() {
+        // keep the socket name of earlier versions rather than `mock.org-mock.sock`
+        server = server.socket_name(DEVICE_PLUGIN_SOCK);
+    }
 
     spawn(server.run());
 
@@ -45,7 +45,6 @@ impl GenericDevicePlugin for MockDevicePlugin {
     async fn get_devices() -> Result<Vec<Device>, Status> {
         let devices = std::fs::read_dir("/dev")
             .map_err(|e| Status::unavailable(e.to_string()))?
-            .into_iter()
             .filter_map(|x| {
                 let id = x.ok().and_then(|x| x.file_name().into_string().ok())?;
                 if id.starts_with("mock") {
@@ -0,0 +1,157 @@
+//! One process advertising several resource names.
+//!
+//! kubelet only knows an integer count per resource name, so heterogeneous
+//! devices (e.g. different memory sizes) are modeled as distinct resources:
+//! one `GenericDevicePlugin` type per resource name, each registered through
+//! its own `GenericDevicePluginServer` on its own socket. All of them share the
+//! same discovery function and only differ in the attribute they select.
+
+use std::{collections::HashMap, time::Duration};
+
+use generic_device_plugin::{
+    ContainerAllocateResponse, ContainerPreferredAllocationResponse, Device, DevicePermissions,
+    DeviceSpec, GenericDevicePlugin, GenericDevicePluginServer, DEVICE_PLUGIN_PATH,
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    spawn,
+};
+use tonic::Status;
+use tracing::info;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let large = GenericDevicePluginServer::<LargeMockDevicePlugin>::new(
+        DEVICE_PLUGIN_PATH.into(),
+        "mock-80gb.sock".to_string(),
+    );
+    let small = GenericDevicePluginServer::<SmallMockDevicePlugin>::new(
+        DEVICE_PLUGIN_PATH.into(),
+        "mock-40gb.sock".to_string(),
+    );
+
+    spawn(large.run());
+    spawn(small.run());
+
+    // k8s is terminating this pod...
+    signal(SignalKind::terminate()).unwrap().recv().await;
+    info!("SIGTERM received, exiting...");
+
+    Ok(())
+}
+
+/// shared discovery: every `/dev/mock-<size>-<n>` node, paired with its size
+fn discover() -> Result<Vec<(String, Device)>, Status> {
+    let devices = std::fs::read_dir("/dev")
+        .map_err(|e| Status::unavailable(e.to_string()))?
+        .filter_map(|x| {
+            let id = x.ok().and_then(|x| x.file_name().into_string().ok())?;
+            let size = id.strip_prefix("mock-")?.split('-').next()?.to_string();
+            Some((
+                size,
+                Device {
+                    id,
+                    health: String::from("Healthy"),
+                    topology: None,
+                },
+            ))
+        })
+        .collect();
+
+    Ok(devices)
+}
+
+/// devices of one size only
+fn discover_sized(size: &str) -> Result<Vec<Device>, Status> {
+    Ok(discover()?
+        .into_iter()
+        .filter(|(s, _)| s == size)
+        .map(|(_, device)| device)
+        .collect())
+}
+
+fn allocate(device_ids: Vec<String>) -> ContainerAllocateResponse {
+    let devices = device_ids
+        .into_iter()
+        .map(|did| {
+            let path = format!("/dev/{did}");
+            DeviceSpec::new(
+                path.clone(),
+                path,
+                DevicePermissions::READ | DevicePermissions::WRITE,
+            )
+        })
+        .collect();
+
+    ContainerAllocateResponse {
+        envs: HashMap::new(),
+        mounts: vec![],
+        devices,
+        annotations: HashMap::new(),
+        cdi_devices: vec![],
+    }
+}
+
+#[derive(Default)]
+pub struct LargeMockDevicePlugin {}
+
+#[async_trait::async_trait]
+impl GenericDevicePlugin for LargeMockDevicePlugin {
+    const PRE_START_REQUIRED: bool = false;
+    const GET_PREFERRED_ALLOCATION_AVAILABLE: bool = false;
+    const RESOURCE_NAME: &'static str = "mock.org/mock-80gb";
+    const DEVICE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+    async fn get_devices() -> Result<Vec<Device>, Status> {
+        discover_sized("80gb")
+    }
+
+    async fn container_allocate(
+        device_ids: Vec<String>,
+    ) -> Result<ContainerAllocateResponse, Status> {
+        Ok(allocate(device_ids))
+    }
+
+    async fn get_container_preferred_allocation(
+        _available_device_ids: Vec<String>,
+        _must_include_device_ids: Vec<String>,
+        _allocation_size: i32,
+    ) -> Result<ContainerPreferredAllocationResponse, Status> {
+        Err(Status::unimplemented(
+            "GET_PREFERRED_ALLOCATION_AVAILABLE = false",
+        ))
+    }
+}
+
+#[derive(Default)]
+pub struct SmallMockDevicePlugin {}
+
+#[async_trait::async_trait]
+impl GenericDevicePlugin for SmallMockDevicePlugin {
+    const PRE_START_REQUIRED: bool = false;
+    const GET_PREFERRED_ALLOCATION_AVAILABLE: bool = false;
+    const RESOURCE_NAME: &'static str = "mock.org/mock-40gb";
+    const DEVICE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+    async fn get_devices() -> Result<Vec<Device>, Status> {
+        discover_sized("40gb")
+    }
+
+    async fn container_allocate(
+        device_ids: Vec<String>,
+    ) -> Result<ContainerAllocateResponse, Status> {
+        Ok(allocate(device_ids))
+    }
+
+    async fn get_container_preferred_allocation(
+        _available_device_ids: Vec<String>,
+        _must_include_device_ids: Vec<String>,
+        _allocation_size: i32,
+    ) -> Result<ContainerPreferredAllocationResponse, Status> {
+        Err(Status::unimplemented(
+            "GET_PREFERRED_ALLOCATION_AVAILABLE = false",
+        ))
+    }
+}
@@ -0,0 +1,133 @@
+//! VFIO passthrough of PCI devices.
+//!
+//! A device is an IOMMU group: the kernel only lets a group be assigned as a
+//! whole, so every PCI function in it must be bound to `vfio-pci`. A container
+//! gets the group node `/dev/vfio/<group>` and the `/dev/vfio/vfio` container
+//! node, plus the PCI addresses in an env var for the workload to open them.
+
+use std::{collections::HashMap, fs, path::Path, time::Duration};
+
+use generic_device_plugin::{
+    ContainerAllocateResponse, ContainerPreferredAllocationResponse, Device, DevicePermissions,
+    DeviceSpec, GenericDevicePlugin, GenericDevicePluginServer, NumaNode, TopologyInfo, HEALTHY,
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    spawn,
+};
+use tonic::Status;
+use tracing::info;
+
+static IOMMU_GROUPS_PATH: &str = "/sys/kernel/iommu_groups";
+static VFIO_CONTROL_PATH: &str = "/dev/vfio/vfio";
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let server = GenericDevicePluginServer::<VfioDevicePlugin>::from_env();
+
+    spawn(server.run());
+
+    // k8s is terminating this pod...
+    signal(SignalKind::terminate()).unwrap().recv().await;
+    info!("SIGTERM received, exiting...");
+
+    Ok(())
+}
+
+/// PCI addresses of the functions in an IOMMU group.
+fn group_pci_addresses(group: &str) -> Vec<String> {
+    let path = Path::new(IOMMU_GROUPS_PATH).join(group).join("devices");
+    fs::read_dir(path)
+        .into_iter()
+        .flatten()
+        .filter_map(|x| x.ok()?.file_name().into_string().ok())
+        .collect()
+}
+
+/// Whether a PCI function is bound to `vfio-pci`.
+fn bound_to_vfio(address: &str) -> bool {
+    let driver = Path::new("/sys/bus/pci/devices")
+        .join(address)
+        .join("driver");
+    fs::read_link(driver)
+        .ok()
+        .and_then(|x| Some(x.file_name()? == "vfio-pci"))
+        .unwrap_or(false)
+}
+
+/// NUMA node of a PCI function, if the platform reports one.
+fn numa_node(address: &str) -> Option<NumaNode> {
+    let path = Path::new("/sys/bus/pci/devices")
+        .join(address)
+        .join("numa_node");
+    NumaNode::checked(fs::read_to_string(path).ok()?.trim().parse().ok()?)
+}
+
+#[derive(Default)]
+pub struct VfioDevicePlugin {}
+
+#[async_trait::async_trait]
+impl GenericDevicePlugin for VfioDevicePlugin {
+    const PRE_START_REQUIRED: bool = false;
+    const GET_PREFERRED_ALLOCATION_AVAILABLE: bool = false;
+    const RESOURCE_NAME: &'static str = "example.com/vfio";
+    const DEVICE_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+    async fn get_devices() -> Result<Vec<Device>, Status> {
+        let groups = fs::read_dir(IOMMU_GROUPS_PATH)
+            .map_err(|e| Status::unavailable(format!("IOMMU disabled? {e}")))?;
+
+        let devices = groups
+            .filter_map(|x| x.ok()?.file_name().into_string().ok())
+            .filter_map(|group| {
+                let addresses = group_pci_addresses(&group);
+                // a group can only be passed through whole
+                if addresses.is_empty() || !addresses.iter().all(|x| bound_to_vfio(x)) {
+                    return None;
+                }
+                let topology = addresses
+                    .iter()
+                    .find_map(|x| numa_node(x))
+                    .map(|node| TopologyInfo { nodes: vec![node] });
+                Some(Device {
+                    id: group,
+                    health: HEALTHY.to_string(),
+                    topology,
+                })
+            })
+            .collect();
+
+        Ok(devices)
+    }
+
+    async fn container_allocate(
+        device_ids: Vec<String>,
+    ) -> Result<ContainerAllocateResponse, Status> {
+        let rw = DevicePermissions::READ | DevicePermissions::WRITE;
+        let mut devices = vec![DeviceSpec::new(VFIO_CONTROL_PATH, VFIO_CONTROL_PATH, rw)];
+        let mut addresses = vec![];
+        for group in &device_ids {
+            let path = format!("/dev/vfio/{group}");
+            devices.push(DeviceSpec::new(path.clone(), path, rw));
+            addresses.extend(group_pci_addresses(group));
+        }
+
+        Ok(ContainerAllocateResponse {
+            envs: HashMap::from([("PCI_DEVICES".to_string(), addresses.join(","))]),
+            devices,
+            ..Default::default()
+        })
+    }
+
+    async fn get_container_preferred_allocation(
+        _available_device_ids: Vec<String>,
+        _must_include_device_ids: Vec<String>,
+        _allocation_size: i32,
+    ) -> Result<ContainerPreferredAllocationResponse, Status> {
+        Err(Status::unimplemented(
+            "GET_PREFERRED_ALLOCATION_AVAILABLE = false",
+        ))
+    }
+}
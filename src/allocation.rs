@@ -0,0 +1,195 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use super::pb::{ContainerPreferredAllocationResponse, Device};
+
+/// Preferred allocation minimizing how many NUMA nodes the allocated devices
+/// span, suitable for `get_container_preferred_allocation` with the topology
+/// manager.
+///
+/// `devices` provides the topology of each device (typically the result of
+/// `get_devices`), devices without topology (or with an invalid negative node
+/// id) are treated as one more node.
+/// Must-include devices are always preferred, the nodes they live on are
+/// filled up first. Then, as long as devices are missing, the smallest node
+/// that can fit all of them is used, or the largest one if none can, so big
+/// nodes are kept for big requests. Fewer than `allocation_size` devices are
+/// returned if not enough are available.
+pub fn numa_aligned_allocation(
+    devices: &[Device],
+    available_ids: &[String],
+    must_include_ids: &[String],
+    allocation_size: i32,
+) -> ContainerPreferredAllocationResponse {
+    let numa_node: HashMap<_, _> = devices
+        .iter()
+        .map(|device| {
+            let node = device
+                .topology
+                .as_ref()
+                .and_then(|x| x.nodes.first())
+                .filter(|x| x.is_valid())
+                .map(|x| x.id);
+            (device.id.as_str(), node)
+        })
+        .collect();
+    let node_of = |id: &str| numa_node.get(id).copied().flatten();
+
+    let mut picked: Vec<String> = must_include_ids.to_vec();
+    let mut need = usize::try_from(allocation_size)
+        .unwrap_or_default()
+        .saturating_sub(picked.len());
+
+    // candidates per node, sorted for a deterministic result
+    let must_include: HashSet<_> = must_include_ids.iter().collect();
+    let mut groups: BTreeMap<Option<i64>, Vec<String>> = BTreeMap::new();
+    for id in available_ids {
+        if !must_include.contains(id) {
+            groups.entry(node_of(id)).or_default().push(id.clone());
+        }
+    }
+    for ids in groups.values_mut() {
+        ids.sort();
+        ids.dedup();
+    }
+
+    // 1. nodes already used by must-include devices
+    let used: BTreeSet<_> = must_include_ids.iter().map(|id| node_of(id)).collect();
+    for node in used {
+        take(&mut groups, node, &mut need, &mut picked);
+    }
+
+    // 2. best fit, or largest node if nothing fits
+    while need > 0 {
+        let fit = groups
+            .iter()
+            .filter(|(_, ids)| ids.len() >= need)
+            .min_by_key(|(_, ids)| ids.len())
+            .or_else(|| groups.iter().max_by_key(|(_, ids)| ids.len()))
+            .map(|(node, _)| *node);
+        match fit {
+            Some(node) => take(&mut groups, node, &mut need, &mut picked),
+            None => break,
+        }
+    }
+
+    ContainerPreferredAllocationResponse::from_ids(picked)
+}
+
+/// Move up to `need` devices of `node` into `picked`.
+fn take(
+    groups: &mut BTreeMap<Option<i64>, Vec<String>>,
+    node: Option<i64>,
+    need: &mut usize,
+    picked: &mut Vec<String>,
+) {
+    if let Some(ids) = groups.remove(&node) {
+        let n = (*need).min(ids.len());
+        picked.extend(ids.into_iter().take(n));
+        *need -= n;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{pb::TopologyInfo, HEALTHY};
+
+    /// a0, a1 on node 0, b0..b3 on node 1
+    fn devices() -> Vec<Device> {
+        let device = |id: &str, node| Device {
+            id: id.to_string(),
+            health: HEALTHY.to_string(),
+            topology: Some(TopologyInfo::numa_node(node)),
+        };
+        vec![
+            device("a0", 0),
+            device("a1", 0),
+            device("b0", 1),
+            device("b1", 1),
+            device("b2", 1),
+            device("b3", 1),
+        ]
+    }
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|x| x.to_string()).collect()
+    }
+
+    fn allocate(must_include: &[&str], size: i32) -> Vec<String> {
+        let devices = devices();
+        let available: Vec<_> = devices.iter().map(|x| x.id.clone()).collect();
+        numa_aligned_allocation(&devices, &available, &ids(must_include), size)
+            .ids()
+            .to_vec()
+    }
+
+    #[test]
+    fn single_node_fit() {
+        // smallest node that fits
+        assert_eq!(allocate(&[], 2), ids(&["a0", "a1"]));
+        assert_eq!(allocate(&[], 3), ids(&["b0", "b1", "b2"]));
+    }
+
+    #[test]
+    fn spill_across_nodes() {
+        assert_eq!(allocate(&[], 5), ids(&["b0", "b1", "b2", "b3", "a0"]));
+        // not enough devices
+        assert_eq!(allocate(&[], 7).len(), 6);
+    }
+
+    #[test]
+    fn must_include_on_other_node() {
+        // the must-include node is filled first, then the smallest fit
+        assert_eq!(allocate(&["b3"], 2), ids(&["b3", "b0"]));
+        assert_eq!(allocate(&["a1"], 3), ids(&["a1", "a0", "b0"]));
+    }
+
+    #[test]
+    fn invariants() {
+        let mut devices = devices();
+        devices.push(Device {
+            id: "c0".to_string(),
+            health: HEALTHY.to_string(),
+            topology: None,
+        });
+        let all: Vec<_> = devices.iter().map(|x| x.id.clone()).collect();
+        // every subset of available devices, must-include devices among them
+        for available_mask in 0u32..1 << all.len() {
+            let available: Vec<_> = (0..all.len())
+                .filter(|i| available_mask & 1 << i != 0)
+                .map(|i| all[i].clone())
+                .collect();
+            for must_include_mask in 0u32..1 << available.len() {
+                let must_include: Vec<_> = (0..available.len())
+                    .filter(|i| must_include_mask & 1 << i != 0)
+                    .map(|i| available[i].clone())
+                    .collect();
+                for size in must_include.len()..=available.len() {
+                    let size = size as i32;
+                    let resp = numa_aligned_allocation(&devices, &available, &must_include, size);
+                    let ctx = (&available, &must_include, size, resp.ids());
+                    assert!(resp.check_allocation_size(size).is_ok(), "{ctx:?}");
+                    assert!(resp.check_must_include(&must_include).is_ok(), "{ctx:?}");
+                    assert!(
+                        resp.ids().iter().all(|id| available.contains(id)),
+                        "{ctx:?}"
+                    );
+                    let mut unique = resp.ids().to_vec();
+                    unique.sort();
+                    unique.dedup();
+                    assert_eq!(unique.len(), resp.ids().len(), "{ctx:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn checks() {
+        let resp = ContainerPreferredAllocationResponse::from_ids(ids(&["a0", "b0"]));
+        assert!(resp.check_allocation_size(2).is_ok());
+        assert!(resp.check_allocation_size(1).is_err());
+        assert!(resp.check_allocation_size(-1).is_err());
+        assert!(resp.check_must_include(&ids(&["b0"])).is_ok());
+        assert!(resp.check_must_include(&ids(&["a0", "a1"])).is_err());
+    }
+}
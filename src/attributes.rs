@@ -0,0 +1,67 @@
+use std::collections::{BTreeMap, HashMap};
+
+use super::pb::{Device, TopologyInfo};
+
+/// A `Device` along with plugin-maintained attributes (memory size, model,
+/// firmware version...).
+///
+/// kubelet's v1beta1 device plugin API only carries a device's id, health and
+/// topology: attributes are never seen by kubelet nor the scheduler. Convert
+/// with `into_device` when returning from `get_devices`. To schedule on an
+/// attribute, advertise one resource name per attribute value (see
+/// `examples/multi_resource.rs`). To pass attributes to the workload, attach
+/// `annotations` to the `ContainerAllocateResponse`, kubelet forwards them to
+/// the container runtime.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AttributedDevice {
+    pub id: String,
+    pub health: String,
+    pub topology: Option<TopologyInfo>,
+    pub attributes: BTreeMap<String, String>,
+}
+
+impl AttributedDevice {
+    pub fn new(device: Device) -> Self {
+        Self {
+            id: device.id,
+            health: device.health,
+            topology: device.topology,
+            attributes: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_attribute(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.attributes.insert(key.into(), value.into());
+        self
+    }
+
+    /// The device as advertised to kubelet, without attributes.
+    pub fn into_device(self) -> Device {
+        Device {
+            id: self.id,
+            health: self.health,
+            topology: self.topology,
+        }
+    }
+
+    /// Attributes as allocate response annotations, keyed
+    /// `<prefix>/<device id>.<attribute key>`.
+    pub fn annotations(&self, prefix: &str) -> HashMap<String, String> {
+        self.attributes
+            .iter()
+            .map(|(key, value)| (format!("{prefix}/{}.{key}", self.id), value.clone()))
+            .collect()
+    }
+}
+
+impl From<Device> for AttributedDevice {
+    fn from(device: Device) -> Self {
+        Self::new(device)
+    }
+}
+
+impl From<AttributedDevice> for Device {
+    fn from(device: AttributedDevice) -> Self {
+        device.into_device()
+    }
+}
@@ -0,0 +1,136 @@
+use std::{fmt::Write, fs, io, path::PathBuf, sync::Arc};
+
+use tokio::{sync::broadcast::error::RecvError, task::spawn_blocking};
+use tracing::{info, warn};
+
+use super::{json::quote, service::GenericDevicePlugin, state::PluginState};
+
+/// CDI spec version written, the oldest one supporting everything used here
+const CDI_VERSION: &str = "0.5.0";
+
+/// A [CDI](https://github.com/cncf-tags/container-device-interface) spec file
+/// describing the discovered devices, kept in sync with discovery by
+/// `GenericDevicePluginServer::cdi_spec`.
+///
+/// Every device is a CDI device named after its (internal) id, exposing
+/// `device_host_path` with `device_permissions`. `container_allocate` can then
+/// answer with `cdi_devices` named by `device_name` instead of device specs.
+/// Ids must be valid CDI device names: alphanumerics, `_`, `-`, `.` and
+/// `:` only.
+#[derive(Debug, Clone)]
+pub struct CdiSpec {
+    kind: String,
+    path: PathBuf,
+}
+
+impl CdiSpec {
+    /// Spec for the CDI `kind` (`vendor/class`, e.g. `mock.org/mock`), written
+    /// to `/etc/cdi/<vendor>-<class>.json`.
+    pub fn new(kind: impl Into<String>) -> Self {
+        let kind = kind.into();
+        let path = PathBuf::from(format!("/etc/cdi/{}.json", kind.replace('/', "-")));
+        Self { kind, path }
+    }
+
+    /// Write the spec to `path` instead, e.g. under `/var/run/cdi`.
+    pub fn path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    /// Fully qualified CDI name of a device, e.g. `mock.org/mock=mock0`.
+    pub fn device_name(&self, id: &str) -> String {
+        format!("{}={id}", self.kind)
+    }
+
+    fn render<DP: GenericDevicePlugin>(&self, ids: &[String]) -> String {
+        let mut out = String::new();
+        let _ = write!(
+            out,
+            "{{\"cdiVersion\":{},\"kind\":{},\"devices\":[",
+            quote(CDI_VERSION),
+            quote(&self.kind)
+        );
+        for (i, id) in ids.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(
+                out,
+                "{{\"name\":{},\"containerEdits\":{{\"deviceNodes\":[{{\"path\":{},\
+                 \"permissions\":{}}}]}}}}",
+                quote(id),
+                quote(&DP::device_host_path(id)),
+                quote(&DP::device_permissions(id).to_string())
+            );
+        }
+        out.push_str("]}");
+        out
+    }
+
+    /// Write the spec for `ids` to a temporary file renamed over `path`, so
+    /// the container runtime never reads a partial spec.
+    fn write<DP: GenericDevicePlugin>(&self, ids: &[String]) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, self.render::<DP>(ids))?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+/// Rewrite `spec` whenever the discovered devices change, starting with the
+/// first discovered list: the spec left by a previous run stays until then.
+pub(crate) async fn sync<DP: GenericDevicePlugin>(spec: CdiSpec, state: Arc<PluginState>) {
+    let mut changes = state.subscribe_changes();
+    state.discovered().await;
+    info!("syncing CDI spec {:?}...", spec.path);
+    loop {
+        let mut ids = state.cached_device_ids();
+        ids.sort();
+        let spec = spec.clone();
+        let written =
+            spawn_blocking(move || spec.write::<DP>(&ids).map_err(|e| (spec.path, e))).await;
+        if let Ok(Err((path, e))) = written {
+            warn!("failed to write CDI spec {path:?}: {e}");
+        }
+        match changes.recv().await {
+            Ok(_) | Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{tests::TestPlugin, Device, HEALTHY};
+
+    #[tokio::test]
+    async fn previous_spec_kept_until_discovery() {
+        let dir = tempfile::tempdir().unwrap();
+        let spec = CdiSpec::new("example.com/test").path(dir.path().join("spec.json"));
+        fs::write(&spec.path, "previous").unwrap();
+        let state = Arc::new(PluginState::default());
+        tokio::spawn(sync::<TestPlugin>(spec.clone(), state.clone()));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(fs::read_to_string(&spec.path).unwrap(), "previous");
+
+        state.set_cached_devices(&[Device {
+            id: "a".to_string(),
+            health: HEALTHY.to_string(),
+            topology: None,
+        }]);
+        for _ in 0..100 {
+            if fs::read_to_string(&spec.path).unwrap() != "previous" {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("spec not written after discovery");
+    }
+}
@@ -0,0 +1,65 @@
+use std::path::Path;
+
+/// cgroup hierarchy used on the node, see `detect_cgroup_version`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgroupVersion {
+    /// legacy or hybrid hierarchy, device access is enforced by the `devices`
+    /// controller (`devices.allow`)
+    V1,
+    /// unified hierarchy, device access is enforced by an eBPF program the
+    /// container runtime attaches to the container cgroup
+    V2,
+}
+
+/// cgroup version of the node, from the hierarchy mounted on `/sys/fs/cgroup`.
+///
+/// `DeviceSpec` permissions mean the same on both: the runtime translates
+/// them to `devices.allow` entries on v1 and to its eBPF device filter on v2.
+/// What differs is how to check them: on v1 the granted rules show up in the
+/// container cgroup's `devices.list`, on v2 there's no such file and denied
+/// accesses only surface as `EPERM` when opening the device.
+pub fn detect_cgroup_version() -> CgroupVersion {
+    cgroup_version_at(Path::new("/sys/fs/cgroup"))
+}
+
+/// cgroup version of the hierarchy mounted on `root`: only the unified
+/// hierarchy has a `cgroup.controllers` file at its root.
+pub fn cgroup_version_at(root: &Path) -> CgroupVersion {
+    if root.join("cgroup.controllers").is_file() {
+        CgroupVersion::V2
+    } else {
+        CgroupVersion::V1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn v1() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("devices")).unwrap();
+        fs::write(root.path().join("devices/devices.list"), "a *:* rwm\n").unwrap();
+        assert_eq!(cgroup_version_at(root.path()), CgroupVersion::V1);
+    }
+
+    #[test]
+    fn v2() {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join("cgroup.controllers"), "cpu io memory\n").unwrap();
+        assert_eq!(cgroup_version_at(root.path()), CgroupVersion::V2);
+    }
+
+    #[test]
+    fn hybrid() {
+        // v1 controllers, the unified hierarchy is mounted below the root
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("devices")).unwrap();
+        fs::create_dir_all(root.path().join("unified")).unwrap();
+        fs::write(root.path().join("unified/cgroup.controllers"), "").unwrap();
+        assert_eq!(cgroup_version_at(root.path()), CgroupVersion::V1);
+    }
+}
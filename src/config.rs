@@ -0,0 +1,81 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    spawn,
+    sync::watch,
+};
+use tokio_stream::wrappers::WatchStream;
+use tracing::{error, info};
+
+use super::service::RescanRequests;
+
+/// Plugin configuration loaded from a file, reloadable at runtime without
+/// restarting the server.
+///
+/// Usually kept in a `static` (e.g. `once_cell::sync::OnceCell`) read by
+/// `get_devices` through `get`, with `rescan_requests` returned from
+/// `GenericDevicePlugin::rescan_requests` so a reload is advertised right away.
+pub struct ReloadableConfig<C> {
+    path: PathBuf,
+    load: fn(&Path) -> anyhow::Result<C>,
+    current: RwLock<Arc<C>>,
+    reloaded: watch::Sender<()>,
+}
+
+impl<C: Send + Sync + 'static> ReloadableConfig<C> {
+    /// Load the configuration at `path`. `load` parses and validates it.
+    pub fn new(
+        path: impl Into<PathBuf>,
+        load: fn(&Path) -> anyhow::Result<C>,
+    ) -> anyhow::Result<Self> {
+        let path = path.into();
+        let current = load(&path)?;
+        Ok(Self {
+            path,
+            load,
+            current: RwLock::new(Arc::new(current)),
+            reloaded: watch::channel(()).0,
+        })
+    }
+
+    /// Current configuration.
+    pub fn get(&self) -> Arc<C> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Load the configuration again, keeping the current one if the new one
+    /// fails to load.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let config = (self.load)(&self.path)?;
+        *self.current.write().unwrap() = Arc::new(config);
+        self.reloaded.send_replace(());
+        Ok(())
+    }
+
+    /// Reload the configuration on every SIGHUP.
+    pub fn reload_on_sighup(&'static self) -> io::Result<()> {
+        let mut hangup = signal(SignalKind::hangup())?;
+        spawn(async move {
+            while hangup.recv().await.is_some() {
+                match self.reload() {
+                    Ok(()) => info!("configuration {:?} reloaded!", self.path),
+                    Err(e) => error!(
+                        "failed to reload configuration {:?}, keeping the previous one: {e}",
+                        self.path
+                    ),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// One item per successful reload.
+    pub fn rescan_requests(&self) -> RescanRequests {
+        Box::pin(WatchStream::from_changes(self.reloaded.subscribe()))
+    }
+}
@@ -0,0 +1,72 @@
+use std::{marker::PhantomData, time::Duration};
+
+use tonic::Status;
+
+use super::{
+    pb::{ContainerAllocateResponse, ContainerPreferredAllocationResponse, Device},
+    service::GenericDevicePlugin,
+    types::HEALTHY,
+};
+
+/// Resource advertised by a `CountDevicePlugin`.
+pub trait CountResource: 'static + Sync + Send {
+    const RESOURCE_NAME: &'static str;
+    /// how many units are advertised
+    const COUNT: usize;
+
+    /// Response to every allocation, nothing is exposed to the container by
+    /// default.
+    fn allocate_response() -> ContainerAllocateResponse {
+        ContainerAllocateResponse::default()
+    }
+}
+
+/// Device plugin advertising `R::COUNT` identical units (`slot-0`,
+/// `slot-1`...) of `R::RESOURCE_NAME`, for software resources without
+/// per-device meaning such as license slots or connection quotas.
+pub struct CountDevicePlugin<R: CountResource> {
+    _phantom: PhantomData<R>,
+}
+
+impl<R: CountResource> Default for CountDevicePlugin<R> {
+    fn default() -> Self {
+        Self {
+            _phantom: PhantomData,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<R: CountResource> GenericDevicePlugin for CountDevicePlugin<R> {
+    const PRE_START_REQUIRED: bool = false;
+    const GET_PREFERRED_ALLOCATION_AVAILABLE: bool = false;
+    const RESOURCE_NAME: &'static str = R::RESOURCE_NAME;
+    // the units never change
+    const DEVICE_POLL_INTERVAL: Duration = Duration::from_secs(3600);
+
+    async fn get_devices() -> Result<Vec<Device>, Status> {
+        Ok((0..R::COUNT)
+            .map(|i| Device {
+                id: format!("slot-{i}"),
+                health: HEALTHY.to_string(),
+                topology: None,
+            })
+            .collect())
+    }
+
+    async fn container_allocate(
+        _device_ids: Vec<String>,
+    ) -> Result<ContainerAllocateResponse, Status> {
+        Ok(R::allocate_response())
+    }
+
+    async fn get_container_preferred_allocation(
+        _available_device_ids: Vec<String>,
+        _must_include_device_ids: Vec<String>,
+        _allocation_size: i32,
+    ) -> Result<ContainerPreferredAllocationResponse, Status> {
+        Err(Status::unimplemented(
+            "GET_PREFERRED_ALLOCATION_AVAILABLE = false",
+        ))
+    }
+}
@@ -0,0 +1,212 @@
+use std::{path::Path, process::Stdio, time::Duration};
+
+use tokio::{process::Command, time::timeout};
+use tonic::Status;
+use tracing::warn;
+
+use super::{
+    pb::Device,
+    types::{HEALTHY, UNHEALTHY},
+};
+
+/// Healthy devices for the paths matching a glob `pattern`, e.g.
+/// `devices_from_glob("video[0-9]*")` or `devices_from_glob("vfio/*")`.
+///
+/// Relative patterns are resolved against `/dev`, and ids are the paths
+/// relative to `/dev` (the full path for devices outside of it), matching the
+/// default `device_host_path`. No match gives an empty list, only an invalid
+/// pattern is an error.
+pub fn devices_from_glob(pattern: &str) -> Result<Vec<Device>, Status> {
+    let pattern = Path::new("/dev").join(pattern);
+    let pattern = pattern.to_string_lossy();
+    let paths = glob::glob(&pattern)
+        .map_err(|e| Status::invalid_argument(format!("invalid pattern {pattern:?}: {e}")))?;
+
+    Ok(paths
+        .filter_map(|path| match path {
+            Ok(path) => Some(path),
+            Err(e) => {
+                warn!("skipping unreadable path: {e}");
+                None
+            }
+        })
+        .map(|path| Device {
+            id: path
+                .strip_prefix("/dev")
+                .unwrap_or(&path)
+                .to_string_lossy()
+                .into_owned(),
+            health: HEALTHY.to_string(),
+            topology: None,
+        })
+        .collect())
+}
+
+/// Include/exclude glob patterns on device ids, applied to every discovered
+/// list before it's advertised, e.g. to exclude a device through config
+/// without touching discovery.
+///
+/// A device is kept if it matches any include pattern (or there are none) and
+/// no exclude pattern. Patterns match whole ids, `*` also matches `/`.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceFilter {
+    include: Vec<glob::Pattern>,
+    exclude: Vec<glob::Pattern>,
+}
+
+impl DeviceFilter {
+    pub fn new(include: &[&str], exclude: &[&str]) -> Result<Self, Status> {
+        let compile = |patterns: &[&str]| {
+            patterns
+                .iter()
+                .map(|pattern| {
+                    glob::Pattern::new(pattern).map_err(|e| {
+                        Status::invalid_argument(format!("invalid pattern {pattern:?}: {e}"))
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(Self {
+            include: compile(include)?,
+            exclude: compile(exclude)?,
+        })
+    }
+
+    pub fn matches(&self, id: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|x| x.matches(id)))
+            && !self.exclude.iter().any(|x| x.matches(id))
+    }
+
+    pub(crate) fn apply(&self, mut devices: Vec<Device>) -> Vec<Device> {
+        devices.retain(|x| self.matches(&x.id));
+        devices
+    }
+}
+
+/// Device id derived from stable hardware identifiers (serial number, PCI
+/// address...), so a physical device keeps its id across reboots and restarts
+/// while kubelet still holds allocations for it.
+///
+/// The id is the 16 hex digits of the 64-bit FNV-1a hash of the parts, each
+/// followed by a NUL byte so `["ab", "c"]` and `["a", "bc"]` differ. The
+/// scheme is fixed, ids never change across crate versions. Collisions are
+/// possible but unlikely (~1e-9 for 200k devices); parts should still uniquely
+/// identify a device on the node, as two devices with equal parts get the same
+/// id.
+pub fn stable_id_from(parts: &[&str]) -> String {
+    const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+
+    let hash = parts
+        .iter()
+        .flat_map(|part| part.bytes().chain([0]))
+        .fold(FNV_OFFSET, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+        });
+    format!("{hash:016x}")
+}
+
+/// Health check running a command per device, e.g. a vendor diagnostic tool:
+/// `CommandHealthCheck::new("nvidia-smi", ["-i", "{id}"], Duration::from_secs(5))`.
+///
+/// `{id}` in the arguments is replaced with the device id. The command runs
+/// without a shell, so ids are never interpreted. Meant for `get_devices` or
+/// `GenericDevicePlugin::device_health`.
+#[derive(Debug, Clone)]
+pub struct CommandHealthCheck {
+    program: String,
+    args: Vec<String>,
+    timeout: Duration,
+}
+
+impl CommandHealthCheck {
+    pub fn new(
+        program: impl Into<String>,
+        args: impl IntoIterator<Item = impl Into<String>>,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            program: program.into(),
+            args: args.into_iter().map(Into::into).collect(),
+            timeout,
+        }
+    }
+
+    /// `HEALTHY` if the command exits successfully, `UNHEALTHY` if it fails,
+    /// times out (it's killed then) or can't be spawned, logging why.
+    pub async fn health(&self, id: &str) -> String {
+        let mut command = Command::new(&self.program);
+        command
+            .args(self.args.iter().map(|arg| arg.replace("{id}", id)))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true);
+
+        let status = match command.spawn() {
+            Ok(mut child) => timeout(self.timeout, child.wait()).await,
+            Err(e) => {
+                warn!("device {id}: failed to spawn {}: {e}", self.program);
+                return UNHEALTHY.to_string();
+            }
+        };
+        match status {
+            Ok(Ok(status)) if status.success() => HEALTHY.to_string(),
+            Ok(Ok(status)) => {
+                warn!("device {id}: {} failed with {status}", self.program);
+                UNHEALTHY.to_string()
+            }
+            Ok(Err(e)) => {
+                warn!("device {id}: failed to wait for {}: {e}", self.program);
+                UNHEALTHY.to_string()
+            }
+            Err(_) => {
+                warn!(
+                    "device {id}: {} timed out after {:?}",
+                    self.program, self.timeout
+                );
+                UNHEALTHY.to_string()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IDS: [&str; 4] = ["gpu0", "gpu1", "nic0", "pci/0000:01:00.0"];
+
+    fn kept(filter: &DeviceFilter) -> Vec<&'static str> {
+        IDS.into_iter().filter(|id| filter.matches(id)).collect()
+    }
+
+    #[test]
+    fn no_patterns() {
+        assert_eq!(kept(&DeviceFilter::default()), IDS);
+        assert_eq!(kept(&DeviceFilter::new(&[], &[]).unwrap()), IDS);
+    }
+
+    #[test]
+    fn include_only() {
+        let filter = DeviceFilter::new(&["gpu*", "pci*"], &[]).unwrap();
+        assert_eq!(kept(&filter), ["gpu0", "gpu1", "pci/0000:01:00.0"]);
+    }
+
+    #[test]
+    fn exclude_only() {
+        let filter = DeviceFilter::new(&[], &["gpu1", "nic?"]).unwrap();
+        assert_eq!(kept(&filter), ["gpu0", "pci/0000:01:00.0"]);
+    }
+
+    #[test]
+    fn exclude_wins() {
+        let filter = DeviceFilter::new(&["gpu*", "nic0"], &["gpu0", "nic*"]).unwrap();
+        assert_eq!(kept(&filter), ["gpu1"]);
+    }
+
+    #[test]
+    fn invalid_pattern() {
+        assert!(DeviceFilter::new(&["gpu["], &[]).is_err());
+    }
+}
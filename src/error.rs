@@ -0,0 +1,113 @@
+use std::{fmt, path::PathBuf, time::Duration};
+
+use tonic::Status;
+
+/// Errors `GenericDevicePluginServer::run` can stop with, returned through
+/// `anyhow::Error` (use `downcast_ref::<ServerError>()` to inspect them).
+#[derive(Debug)]
+pub enum ServerError {
+    /// `get_devices` failed with an error classified as
+    /// `DiscoveryOutcome::Fatal`.
+    DiscoveryFatal(Status),
+    /// binding the device plugin socket didn't complete within the configured
+    /// `bind_timeout`.
+    BindTimeout(Duration),
+    /// the device plugin directory is on a read-only filesystem, so the
+    /// socket can't be created.
+    ReadOnlyDirectory(PathBuf),
+    /// the endpoint registered to kubelet isn't the file name of the socket
+    /// the server listens on, so kubelet couldn't connect back.
+    EndpointMismatch {
+        bound: Option<PathBuf>,
+        endpoint: String,
+    },
+    /// kubelet rejected the device plugin API version, `supported` lists the
+    /// versions it reported (possibly none).
+    VersionMismatch {
+        requested: String,
+        supported: Vec<String>,
+    },
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DiscoveryFatal(status) => write!(f, "device discovery failed fatally: {status}"),
+            Self::BindTimeout(timeout) => {
+                write!(
+                    f,
+                    "binding device plugin socket timed out after {timeout:?}"
+                )
+            }
+            Self::ReadOnlyDirectory(path) => {
+                write!(f, "device plugin directory {path:?} is read-only")
+            }
+            Self::EndpointMismatch { bound, endpoint } => write!(
+                f,
+                "registered endpoint {endpoint:?} doesn't match the bound socket {bound:?}"
+            ),
+            Self::VersionMismatch {
+                requested,
+                supported,
+            } => write!(
+                f,
+                "kubelet doesn't support device plugin API {requested}, supported versions: \
+                 {supported:?}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+/// Errors for trait methods to return, with consistent `Status` codes.
+///
+/// | variant               | code                  | meaning for kubelet                         |
+/// |-----------------------|-----------------------|---------------------------------------------|
+/// | `DeviceUnavailable`   | `UNAVAILABLE`         | device is busy/gone now, retrying may work  |
+/// | `DeviceNotFound`      | `NOT_FOUND`           | kubelet asked for an unknown device id      |
+/// | `InvalidRequest`      | `INVALID_ARGUMENT`    | the request itself is malformed             |
+/// | `ResourceExhausted`   | `RESOURCE_EXHAUSTED`  | not enough devices to satisfy the request   |
+/// | `Unsupported`         | `UNIMPLEMENTED`       | the RPC isn't supported by this plugin      |
+/// | `Internal`            | `INTERNAL`            | a bug or unexpected failure in the plugin   |
+///
+/// kubelet fails the pod admission on any error from `allocate`, the codes
+/// mainly make the failure reason readable in pod events and plugin logs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PluginError {
+    DeviceUnavailable(String),
+    DeviceNotFound(String),
+    InvalidRequest(String),
+    ResourceExhausted(String),
+    Unsupported(String),
+    Internal(String),
+}
+
+impl fmt::Display for PluginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DeviceUnavailable(msg) => write!(f, "device unavailable: {msg}"),
+            Self::DeviceNotFound(id) => write!(f, "device not found: {id}"),
+            Self::InvalidRequest(msg) => write!(f, "invalid request: {msg}"),
+            Self::ResourceExhausted(msg) => write!(f, "resource exhausted: {msg}"),
+            Self::Unsupported(msg) => write!(f, "unsupported: {msg}"),
+            Self::Internal(msg) => write!(f, "internal error: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for PluginError {}
+
+impl From<PluginError> for Status {
+    fn from(e: PluginError) -> Self {
+        let msg = e.to_string();
+        match e {
+            PluginError::DeviceUnavailable(_) => Status::unavailable(msg),
+            PluginError::DeviceNotFound(_) => Status::not_found(msg),
+            PluginError::InvalidRequest(_) => Status::invalid_argument(msg),
+            PluginError::ResourceExhausted(_) => Status::resource_exhausted(msg),
+            PluginError::Unsupported(_) => Status::unimplemented(msg),
+            PluginError::Internal(_) => Status::internal(msg),
+        }
+    }
+}
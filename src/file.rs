@@ -0,0 +1,163 @@
+use std::{fs, marker::PhantomData, path, time::Duration};
+
+use notify::{recommended_watcher, Event, RecursiveMode, Watcher};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tonic::Status;
+use tracing::error;
+
+use super::{
+    pb::{ContainerAllocateResponse, ContainerPreferredAllocationResponse, Device},
+    service::{GenericDevicePlugin, RescanRequests},
+    types::HEALTHY,
+};
+
+/// Where a `FileBackedDevicePlugin` reads its devices from.
+pub trait DeviceListFile: 'static + Sync + Send {
+    const RESOURCE_NAME: &'static str;
+    /// Device list, a JSON array of devices with an `id` and optionally a
+    /// `health` (`Healthy` if omitted), e.g.
+    /// `[{"id": "dev0"}, {"id": "dev1", "health": "Unhealthy"}]`. Relative
+    /// paths are resolved against the working directory.
+    const PATH: &'static str;
+    /// The file is watched for changes, this is only a fallback.
+    const POLL_INTERVAL: Duration = Duration::from_secs(60);
+}
+
+/// Device plugin advertising the devices listed in `F::PATH`, picking up
+/// edits live. Meant for fake or virtual resources: allocation doesn't expose
+/// anything to the container.
+pub struct FileBackedDevicePlugin<F: DeviceListFile> {
+    _phantom: PhantomData<F>,
+}
+
+impl<F: DeviceListFile> Default for FileBackedDevicePlugin<F> {
+    fn default() -> Self {
+        Self {
+            _phantom: PhantomData,
+        }
+    }
+}
+
+/// device as listed in `DeviceListFile::PATH`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeviceEntry {
+    id: String,
+    health: Option<String>,
+}
+
+fn parse_device_list(content: &str) -> Result<Vec<Device>, serde_json::Error> {
+    let entries: Vec<DeviceEntry> = serde_json::from_str(content)?;
+    Ok(entries
+        .into_iter()
+        .map(|entry| Device {
+            id: entry.id,
+            health: entry.health.unwrap_or_else(|| HEALTHY.to_string()),
+            topology: None,
+        })
+        .collect())
+}
+
+#[async_trait::async_trait]
+impl<F: DeviceListFile> GenericDevicePlugin for FileBackedDevicePlugin<F> {
+    const PRE_START_REQUIRED: bool = false;
+    const GET_PREFERRED_ALLOCATION_AVAILABLE: bool = false;
+    const RESOURCE_NAME: &'static str = F::RESOURCE_NAME;
+    const DEVICE_POLL_INTERVAL: Duration = F::POLL_INTERVAL;
+
+    async fn get_devices() -> Result<Vec<Device>, Status> {
+        let content = fs::read_to_string(F::PATH)
+            .map_err(|e| Status::unavailable(format!("failed to read {}: {e}", F::PATH)))?;
+        parse_device_list(&content)
+            .map_err(|e| Status::unavailable(format!("failed to parse {}: {e}", F::PATH)))
+    }
+
+    fn rescan_requests() -> Option<RescanRequests> {
+        // watch the directory, editors usually replace the file instead of
+        // writing to it
+        let path = match path::absolute(F::PATH) {
+            Ok(path) => path,
+            Err(e) => {
+                error!(
+                    "failed to resolve {}, falling back to polling: {e}",
+                    F::PATH
+                );
+                return None;
+            }
+        };
+        let dir = path.parent()?.to_path_buf();
+        let (tx, rx) = mpsc::channel(1);
+        let watcher = recommended_watcher(move |res: notify::Result<Event>| match res {
+            Ok(event)
+                if event
+                    .paths
+                    .iter()
+                    .any(|x| x.file_name() == path.file_name()) =>
+            {
+                let _ = tx.try_send(());
+            }
+            Ok(_) => {}
+            Err(e) => error!("failed to watch {}: {e}", F::PATH),
+        })
+        .and_then(|mut watcher| {
+            watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+            Ok(watcher)
+        });
+        let watcher = match watcher {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                error!("failed to watch {}, falling back to polling: {e}", F::PATH);
+                return None;
+            }
+        };
+        // the stream owns the watcher, so it stops watching once dropped
+        Some(Box::pin(ReceiverStream::new(rx).map(move |x| {
+            let _ = &watcher;
+            x
+        })))
+    }
+
+    async fn container_allocate(
+        _device_ids: Vec<String>,
+    ) -> Result<ContainerAllocateResponse, Status> {
+        Ok(ContainerAllocateResponse::default())
+    }
+
+    async fn get_container_preferred_allocation(
+        _available_device_ids: Vec<String>,
+        _must_include_device_ids: Vec<String>,
+        _allocation_size: i32,
+    ) -> Result<ContainerPreferredAllocationResponse, Status> {
+        Err(Status::unimplemented(
+            "GET_PREFERRED_ALLOCATION_AVAILABLE = false",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_list() {
+        let devices =
+            parse_device_list(r#"[{"id": "dev0"}, {"id": "dev1", "health": "Unhealthy"}]"#)
+                .unwrap();
+        let devices: Vec<_> = devices
+            .iter()
+            .map(|x| (x.id.as_str(), x.health.as_str()))
+            .collect();
+        assert_eq!(devices, [("dev0", HEALTHY), ("dev1", "Unhealthy")]);
+        assert!(parse_device_list("[]").unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_device_list() {
+        assert!(parse_device_list("dev0 Healthy").is_err());
+        assert!(parse_device_list(r#"[{"health": "Healthy"}]"#).is_err());
+        assert!(parse_device_list(r#"[{"id": "dev0", "heath": "Unhealthy"}]"#).is_err());
+        assert!(parse_device_list(r#"[{"id": "dev0"}"#).is_err());
+    }
+}
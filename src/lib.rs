@@ -1,122 +1,1279 @@
-use std::{fs, io::ErrorKind, marker::PhantomData, path::PathBuf};
+use std::{
+    env,
+    ffi::OsStr,
+    fs,
+    future::{pending, Future},
+    io::{self, ErrorKind},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use anyhow::bail;
-use notify::{recommended_watcher, RecursiveMode, Watcher};
+use anyhow::{anyhow, bail, Context};
+use notify::{recommended_watcher, Event, RecursiveMode, Watcher};
 use tokio::{
     net::{UnixListener, UnixStream},
+    select,
+    signal::unix::{signal, SignalKind},
     spawn,
-    sync::watch,
+    sync::{mpsc, watch},
+    task::spawn_blocking,
+    time::{interval_at, sleep, timeout, Instant, Interval, MissedTickBehavior},
 };
 use tokio_stream::wrappers::UnixListenerStream;
 use tonic::{
-    transport::{Endpoint, Server, Uri},
-    Request,
+    transport::{Channel, Endpoint, Server, Uri},
+    Request, Status,
 };
-use tower::service_fn;
+use tower::{limit::ConcurrencyLimitLayer, service_fn, util::option_layer, Service};
 use tracing::{error, info, warn};
 
-use self::pb::{
-    device_plugin_server::DevicePluginServer, registration_client::RegistrationClient,
-    DevicePluginOptions, RegisterRequest,
-};
+#[cfg(target_os = "linux")]
+pub use self::cgroup::{cgroup_version_at, detect_cgroup_version, CgroupVersion};
 pub use self::{
+    allocation::numa_aligned_allocation,
+    attributes::AttributedDevice,
+    cdi::CdiSpec,
+    config::ReloadableConfig,
+    count::{CountDevicePlugin, CountResource},
+    discovery::{devices_from_glob, stable_id_from, CommandHealthCheck, DeviceFilter},
+    error::{PluginError, ServerError},
+    file::{DeviceListFile, FileBackedDevicePlugin},
+    lifecycle::{RestartReason, ShutdownReason, ShutdownReport, WatchErrorPolicy},
     pb::{
         CdiDevice, ContainerAllocateResponse, ContainerPreferredAllocationResponse, Device,
-        DeviceSpec, Mount, NumaNode, TopologyInfo,
+        DevicePluginOptions, DeviceSpec, Mount, NumaNode, TopologyInfo,
+    },
+    service::{
+        DeviceStream, DiscoveryOutcome, GenericDevicePlugin, HealthUpdate, HealthUpdates,
+        RescanRequests, StreamErrorPolicy,
+    },
+    state::{
+        DeviceDiff, GenericDevicePluginHandle, LatencySummary, PluginMetrics, ServerEvent,
+        ServerPhase, VersionInfo,
     },
-    service::GenericDevicePlugin,
+    types::{DevicePermissions, DeviceSpecTemplate, HEALTHY, UNHEALTHY},
+};
+use self::{
+    limit::TokenBucket,
+    pb::{
+        device_plugin_server::DevicePluginServer, registration_client::RegistrationClient,
+        RegisterRequest,
+    },
+    service::{GenericDevicePluginService, ServiceConfig},
+    state::PluginState,
 };
 
+mod allocation;
+mod attributes;
+mod cdi;
+#[cfg(target_os = "linux")]
+mod cgroup;
+mod config;
+mod count;
+mod discovery;
+mod error;
+mod file;
+mod json;
+mod lifecycle;
+mod limit;
 mod service;
+mod snapshot;
+mod state;
+#[cfg(feature = "status")]
+mod status;
+mod types;
+// generated, its doc comments are copied from the proto file
+#[allow(clippy::all)]
 mod pb {
     tonic::include_proto!("v1beta1");
 }
 
 static VERSION: &str = "v1beta1";
 static KUBELET_SOCK: &str = "kubelet.sock";
+/// Placeholder URI for the kubelet registration channel: connections go
+/// through the unix socket connector, so it's only parsed (and sent as the
+/// HTTP/2 authority), never resolved nor dialed. It doesn't need IPv6.
+static REGISTRATION_URI: &str = "http://[::]:50051";
+/// Directory kubelet scans for device plugin sockets by default.
+pub static DEVICE_PLUGIN_PATH: &str = "/var/lib/kubelet/device-plugins/";
+/// Device plugin directories of common distributions, in probe order.
+static DEVICE_PLUGIN_PATHS: &[&str] = &[
+    DEVICE_PLUGIN_PATH,
+    // microk8s
+    "/var/snap/microk8s/common/var/lib/kubelet/device-plugins/",
+    // k0s
+    "/var/lib/k0s/kubelet/device-plugins/",
+];
 
+/// First kubelet device plugin directory containing `kubelet.sock`, probing
+/// in order `/var/lib/kubelet/device-plugins/` (upstream default, also used
+/// by kubeadm, k3s and most distributions), then microk8s'
+/// `/var/snap/microk8s/common/var/lib/kubelet/device-plugins/` and k0s'
+/// `/var/lib/k0s/kubelet/device-plugins/`.
+pub fn detect_device_plugin_dir() -> Option<PathBuf> {
+    DEVICE_PLUGIN_PATHS
+        .iter()
+        .map(PathBuf::from)
+        .find(|dir| dir.join(KUBELET_SOCK).exists())
+}
+
+/// how many times removing a socket file is attempted, see `remove_socket`
+const REMOVE_ATTEMPTS: u32 = 3;
+const REMOVE_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// how often a removed device plugin directory is checked for
+const DIR_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+type RegistrationEndpointFn = dyn Fn() -> anyhow::Result<Endpoint> + Send + Sync;
+
+/// Serves one `GenericDevicePlugin` on `dir_path/socket_name` and registers it
+/// to kubelet under `DP::RESOURCE_NAME` (see `resource_name`).
+///
+/// kubelet schedules each resource name as a plain integer count. To expose
+/// heterogeneous devices (e.g. different memory sizes) as separate schedulable
+/// resources, implement one plugin type per resource name, filtering a shared
+/// discovery by device attribute in `get_devices`, and run one server per type
+/// (each with its own socket) from the same process. See
+/// `examples/multi_resource.rs`.
+///
+/// # Runtime requirements
+///
+/// `run` and `run_with_listener` must be polled within a tokio runtime with
+/// IO and time enabled (`Builder::enable_all`). They `tokio::spawn` the gRPC
+/// server, and the service spawns the discovery task and one forwarding task
+/// per ListAndWatch stream onto that same runtime, so no `Send` executor or
+/// dedicated thread is needed and a current-thread runtime works too.
+/// `bind_timeout` additionally uses `spawn_blocking`, and the socket watcher
+/// runs its own thread. On a current-thread runtime, trait methods doing
+/// blocking I/O stall every task: offload it with `spawn_blocking`.
 pub struct GenericDevicePluginServer<DP: GenericDevicePlugin> {
     dir_path: PathBuf,
     socket_name: String,
+    service_config: ServiceConfig,
+    #[cfg(feature = "status")]
+    status_addr: Option<std::net::SocketAddr>,
+    cdi_spec: Option<CdiSpec>,
+    rescan_on_sighup: bool,
+    state: Arc<PluginState>,
+    strict_dir_path: bool,
+    reregister_interval: Option<Duration>,
+    concurrency_limit: Option<usize>,
+    registration_endpoint: Option<Arc<RegistrationEndpointFn>>,
+    bind_timeout: Option<Duration>,
+    registration_client: Mutex<Option<RegistrationClient<Channel>>>,
+    resource_name: Option<String>,
+    watch_error_policy: WatchErrorPolicy,
+    watch_socket: bool,
+    registration_uri: Uri,
+    /// initial and max delay between restarts
+    restart_backoff: (Duration, Duration),
+    shutdown_grace: Duration,
     _phantom: PhantomData<DP>,
 }
 
+/// Whether `dir` is one of `DEVICE_PLUGIN_PATHS`, as is or once symlinks are
+/// resolved (e.g. `/var/lib/kubelet` linking to another disk), which works
+/// before `dir` exists too.
+fn is_known_dir(dir: &Path) -> bool {
+    let resolved = fs::canonicalize(dir).ok();
+    DEVICE_PLUGIN_PATHS.iter().map(Path::new).any(|known| {
+        dir == known
+            || resolved.as_deref() == Some(known)
+            || resolved.is_some() && fs::canonicalize(known).ok() == resolved
+    })
+}
+
 impl<DP: GenericDevicePlugin> GenericDevicePluginServer<DP> {
     pub fn new(dir_path: PathBuf, socket_name: String) -> Self {
         Self {
             dir_path,
             socket_name,
+            service_config: ServiceConfig::default(),
+            #[cfg(feature = "status")]
+            status_addr: None,
+            cdi_spec: None,
+            rescan_on_sighup: false,
+            state: Arc::default(),
+            strict_dir_path: false,
+            reregister_interval: None,
+            concurrency_limit: None,
+            registration_endpoint: None,
+            bind_timeout: None,
+            registration_client: Mutex::new(None),
+            resource_name: None,
+            watch_error_policy: WatchErrorPolicy::default(),
+            watch_socket: true,
+            registration_uri: Uri::from_static(REGISTRATION_URI),
+            restart_backoff: (Duration::from_secs(1), Duration::from_secs(30)),
+            shutdown_grace: Duration::from_secs(10),
             _phantom: PhantomData,
         }
     }
 
+    /// Server configured from the environment, as usually set on a DaemonSet:
+    /// - `DEVICE_PLUGIN_PATH`: plugin directory, `detect_device_plugin_dir`
+    ///   or else `DEVICE_PLUGIN_PATH` by default
+    /// - `DEVICE_PLUGIN_SOCK`: socket name, `DP::RESOURCE_NAME` with `/`
+    ///   replaced by `-` and a `.sock` suffix by default
+    /// - `DEVICE_PLUGIN_RESOURCE_NAME`: see `resource_name`, unset by default
+    pub fn from_env() -> Self {
+        let dir_path = env::var_os("DEVICE_PLUGIN_PATH")
+            .map(PathBuf::from)
+            .or_else(detect_device_plugin_dir)
+            .unwrap_or_else(|| DEVICE_PLUGIN_PATH.into());
+        let socket_name = env::var("DEVICE_PLUGIN_SOCK")
+            .unwrap_or_else(|_| format!("{}.sock", DP::RESOURCE_NAME.replace('/', "-")));
+        let server = Self::new(dir_path, socket_name);
+        match env::var("DEVICE_PLUGIN_RESOURCE_NAME") {
+            Ok(name) => server.resource_name(name),
+            Err(_) => server,
+        }
+    }
+
+    /// Name of the socket created in `dir_path`, replacing the one given to
+    /// `new` or taken from `DEVICE_PLUGIN_SOCK`.
+    pub fn socket_name(mut self, socket_name: impl Into<String>) -> Self {
+        self.socket_name = socket_name.into();
+        self
+    }
+
+    /// Only keep the latest device list when kubelet is slow to consume the
+    /// ListAndWatch stream, instead of queueing every intermediate list.
+    /// Disabled by default (lossless).
+    pub fn keep_latest(mut self, enabled: bool) -> Self {
+        self.service_config.keep_latest = enabled;
+        self
+    }
+
+    /// Restrict the permissions of every `DeviceSpec` returned by allocate to
+    /// `allowed`, whatever the plugin requests, e.g. to never grant mknod:
+    /// other permissions are dropped with a warning, and a spec left with none
+    /// fails the allocation. Unrestricted by default.
+    pub fn allowed_permissions(mut self, allowed: DevicePermissions) -> Self {
+        self.service_config.allowed_permissions = Some(allowed);
+        self
+    }
+
+    /// Serve the server state (phase, advertised devices, restarts, recent
+    /// events) as JSON over HTTP on `127.0.0.1:port`.
+    #[cfg(feature = "status")]
+    pub fn status_port(self, port: u16) -> Self {
+        self.status_addr((std::net::Ipv4Addr::LOCALHOST, port).into())
+    }
+
+    /// Serve the server state as JSON over HTTP on `addr`, e.g. `0.0.0.0:port`
+    /// to scrape it from outside the node. It exposes devices and allocation
+    /// state to anyone who can reach `addr`.
+    #[cfg(feature = "status")]
+    pub fn status_addr(mut self, addr: std::net::SocketAddr) -> Self {
+        self.status_addr = Some(addr);
+        self
+    }
+
+    /// Keep a CDI spec file describing the discovered devices up to date while
+    /// running. No spec is written by default.
+    pub fn cdi_spec(mut self, spec: CdiSpec) -> Self {
+        self.cdi_spec = Some(spec);
+        self
+    }
+
+    /// Poll devices right away on every SIGHUP, e.g. for operators to pick up
+    /// a driver reload without restarting the plugin. Disabled by default.
+    pub fn rescan_on_sighup(mut self, enabled: bool) -> Self {
+        self.rescan_on_sighup = enabled;
+        self
+    }
+
+    /// Fail to run instead of only warning when `dir_path` is none of the
+    /// directories probed by `detect_device_plugin_dir`, where kubelet looks
+    /// for device plugins by default.
+    pub fn strict_dir_path(mut self, enabled: bool) -> Self {
+        self.strict_dir_path = enabled;
+        self
+    }
+
+    /// Register to kubelet again every `interval` while serving, in addition
+    /// to after each socket change, to recover from missed socket events.
+    pub fn reregister_interval(mut self, interval: Duration) -> Self {
+        self.reregister_interval = Some(interval);
+        self
+    }
+
+    /// Bound how many RPCs are handled concurrently, across all kubelet
+    /// connections. Unlimited by default.
+    ///
+    /// A request holds its slot until its response is ready, so a burst of
+    /// `Allocate` calls queues up instead of running every `container_allocate`
+    /// at once; a ListAndWatch stream only holds one while being established.
+    /// Trait methods doing blocking I/O should still move it off the runtime
+    /// (e.g. `tokio::task::spawn_blocking`), as the limit doesn't free worker
+    /// threads.
+    pub fn concurrency_limit(mut self, limit: usize) -> Self {
+        self.concurrency_limit = Some(limit);
+        self
+    }
+
+    /// Reject allocations of a device less than `cooldown` after its previous
+    /// one with `UNAVAILABLE` and a retry hint, for hardware that can't take
+    /// rapid allocate/reset cycles (e.g. firmware reinit). kubelet fails the
+    /// pod admission then, leaving the retry to the pod's controller.
+    /// `PreStartContainer` isn't limited, it follows the allocation it
+    /// belongs to. No cooldown by default.
+    pub fn allocate_cooldown(mut self, cooldown: Duration) -> Self {
+        self.service_config.allocate_cooldown = Some(cooldown);
+        self
+    }
+
+    /// Limit `Allocate` calls to `per_second` on average with bursts of up to
+    /// `burst`, rejecting the rest with `RESOURCE_EXHAUSTED`, to protect the
+    /// device backend when many pods start at once. Unlimited by default.
+    ///
+    /// # Panics
+    ///
+    /// If `per_second` isn't a positive, finite number.
+    pub fn allocate_rate_limit(mut self, per_second: f64, burst: u32) -> Self {
+        assert!(
+            per_second.is_finite() && per_second > 0.0,
+            "allocate rate limit must be positive and finite, got {per_second}"
+        );
+        self.service_config.allocate_rate_limit =
+            Some(Arc::new(Mutex::new(TokenBucket::new(per_second, burst))));
+        self
+    }
+
+    /// Reject ListAndWatch streams with `RESOURCE_EXHAUSTED` while `max` are
+    /// already served, e.g. when kubelet reconnects in a storm before closed
+    /// streams were noticed. A stream counts until its forwarding task ends.
+    /// Unlimited by default.
+    pub fn max_streams(mut self, max: usize) -> Self {
+        self.service_config.max_streams = Some(max);
+        self
+    }
+
+    /// Reject allocating a device already allocated with
+    /// `FAILED_PRECONDITION`. Kubelet never reports devices freed, so a device
+    /// stays reserved until discovery stops reporting it or
+    /// `GenericDevicePluginHandle::release` is called. Disabled by default.
+    pub fn exclusive_allocation(mut self, enabled: bool) -> Self {
+        self.service_config.exclusive_allocation = enabled;
+        self
+    }
+
+    /// Fail `Allocate`, `GetPreferredAllocation` and `PreStartContainer` with
+    /// `DEADLINE_EXCEEDED` when the plugin takes longer than `timeout`. Only
+    /// used for requests without a deadline of their own (`grpc-timeout`),
+    /// which is always honored. Unlimited by default.
+    pub fn rpc_timeout(mut self, timeout: Duration) -> Self {
+        self.service_config.rpc_timeout = Some(timeout);
+        self
+    }
+
+    /// Only advertise discovered devices accepted by `filter`. Filtered out
+    /// devices are treated as absent. Every device is kept by default.
+    pub fn device_filter(mut self, filter: DeviceFilter) -> Self {
+        self.service_config.device_filter = Some(filter);
+        self
+    }
+
+    /// What ListAndWatch streams send when `get_devices` fails (the error is
+    /// logged either way). Sends the error by default.
+    pub fn stream_error_policy(mut self, policy: StreamErrorPolicy) -> Self {
+        self.service_config.stream_error_policy = policy;
+        self
+    }
+
+    /// Connect to kubelet for registration through the `Endpoint` returned by
+    /// `endpoint` (e.g. with TLS or credentials configured) instead of
+    /// `dir_path/kubelet.sock`.
+    ///
+    /// Only meant for non-standard environments tunneling the registration
+    /// service, kubelet itself always listens on the unauthenticated socket.
+    pub fn registration_endpoint(
+        mut self,
+        endpoint: impl Fn() -> anyhow::Result<Endpoint> + Send + Sync + 'static,
+    ) -> Self {
+        self.registration_endpoint = Some(Arc::new(endpoint));
+        self
+    }
+
+    /// Fail `run` with `ServerError::BindTimeout` if binding the device plugin
+    /// socket, or probing and removing a stale one, takes longer than
+    /// `timeout`, e.g. on a hung network filesystem. Waits forever by default.
+    pub fn bind_timeout(mut self, timeout: Duration) -> Self {
+        self.bind_timeout = Some(timeout);
+        self
+    }
+
+    /// Register to kubelet under `name` instead of `DP::RESOURCE_NAME`, e.g.
+    /// to deploy the same plugin under different names per node pool.
+    pub fn resource_name(mut self, name: impl Into<String>) -> Self {
+        self.resource_name = Some(name.into());
+        self
+    }
+
+    /// What to do when watching the socket file fails. Restarts by default,
+    /// `Ignore` or `Rewatch` avoid restart storms on filesystems whose watcher
+    /// reports spurious errors.
+    pub fn watch_error_policy(mut self, policy: WatchErrorPolicy) -> Self {
+        self.watch_error_policy = policy;
+        self
+    }
+
+    /// Whether `run` watches its socket to restart when it's removed (usually
+    /// kubelet restarting), enabled by default. When disabled no `notify`
+    /// watcher is created and the server only stops on error or when `run`
+    /// is dropped, e.g. against a mock kubelet or without inotify.
+    pub fn watch_socket(mut self, enabled: bool) -> Self {
+        self.watch_socket = enabled;
+        self
+    }
+
+    /// Placeholder URI of the registration channel to kubelet's unix socket,
+    /// `http://[::]:50051` by default. It's never dialed, only override it if
+    /// something on the path rejects that authority. It must be an `http` URI
+    /// with an authority, registration fails otherwise. Ignored with
+    /// `registration_endpoint`.
+    pub fn registration_uri(mut self, uri: Uri) -> Self {
+        self.registration_uri = uri;
+        self
+    }
+
+    /// Wait `initial` before restarting on a socket change, doubling up to
+    /// `max` while restarts follow each other within `max`, so a flapping
+    /// socket doesn't spin. 1s up to 30s by default, `Duration::ZERO` restarts
+    /// right away.
+    pub fn restart_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.restart_backoff = (initial, max.max(initial));
+        self
+    }
+
+    /// How long in-flight RPCs and streams may delay a restart before the
+    /// server is aborted, so a wedged stream can't block it forever. 10s by
+    /// default.
+    pub fn shutdown_grace(mut self, grace: Duration) -> Self {
+        self.shutdown_grace = grace;
+        self
+    }
+
+    /// Build metadata of the plugin (e.g. git commit, build date), logged on
+    /// startup and available through `GenericDevicePluginHandle::version`.
+    pub fn build_info(self, build: impl Into<String>) -> Self {
+        self.state.set_build_info(build.into());
+        self
+    }
+
+    /// Persist the advertised devices and allocation cooldowns to `path`,
+    /// rewritten in the background after they change, and restore them from
+    /// it now, so they survive plugin restarts. A missing, corrupt or outdated
+    /// file is ignored. Nothing is persisted by default.
+    pub fn state_file(self, path: impl Into<PathBuf>) -> Self {
+        self.state.set_state_file(path.into());
+        self
+    }
+
+    /// Get a handle to inspect the server once it is running.
+    pub fn handle(&self) -> GenericDevicePluginHandle {
+        GenericDevicePluginHandle {
+            state: self.state.clone(),
+        }
+    }
+
     /// 1. clean up & bind socket
     /// 2. watch socket file (kubelet restart)
     /// 3. start device plugin server
     /// 4. register to kubelet
     /// 5. clean up & goto 1 if socket file changed (graceful)
-    pub async fn run(self) -> anyhow::Result<()> {
+    ///
+    /// Only returns on failure, with a [`ShutdownReason`] and a
+    /// [`ShutdownReport`] attached as context.
+    pub async fn run(mut self) -> anyhow::Result<()> {
+        let res = self.run_loop().await;
+        self.stopped(res)
+    }
+
+    async fn run_loop(&mut self) -> anyhow::Result<()> {
+        self.prepare().context(ShutdownReason::InvalidDirectory)?;
+        preflight::<DP>().await?;
+        self.wait_for_dir().await;
+        self.resolve_dir()
+            .context(ShutdownReason::InvalidDirectory)?;
         let socket_path = self.dir_path.join(&self.socket_name);
+        let (initial_backoff, max_backoff) = self.restart_backoff;
+        let mut backoff = initial_backoff;
 
         loop {
-            match std::os::unix::net::UnixStream::connect(&socket_path) {
+            self.wait_for_dir().await;
+            let probe = {
+                let path = socket_path.clone();
+                self.fs_op(move || std::os::unix::net::UnixStream::connect(path))
+                    .await
+                    .context(ShutdownReason::SocketUnavailable)?
+            };
+            match probe {
                 Err(e) if e.kind() == ErrorKind::NotFound => {}
-                Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
-                    fs::remove_file(&socket_path)?
-                }
-                Err(e) => bail!("unable to ensure uds is available: {e:?}"),
-                Ok(_) => bail!("active unix socket connect exist on {socket_path:?}"),
+                Err(e) if e.kind() == ErrorKind::ConnectionRefused => self
+                    .remove_socket(&socket_path)
+                    .await
+                    .context(ShutdownReason::SocketUnavailable)?,
+                Err(e) => Err(anyhow!("unable to ensure uds is available: {e:?}"))
+                    .context(ShutdownReason::SocketUnavailable)?,
+                Ok(_) => Err(anyhow!(
+                    "active unix socket connect exist on {socket_path:?}"
+                ))
+                .context(ShutdownReason::SocketUnavailable)?,
+            }
+
+            let uds = self
+                .bind(&socket_path)
+                .await
+                .context(ShutdownReason::BindFailed)?;
+
+            let (tx, rx) = watch::channel(None);
+            let tx = Arc::new(tx);
+            let rewatch = if self.watch_socket {
+                let (rewatch_tx, mut rewatch_rx) = mpsc::unbounded_channel();
+                let mut watcher = recommended_watcher({
+                    let tx = tx.clone();
+                    let policy = self.watch_error_policy;
+                    let state = self.state.clone();
+                    move |res: notify::Result<Event>| {
+                        let reason = match res {
+                            Ok(event) => RestartReason::from(event.kind),
+                            Err(e) => {
+                                error!("failed to watch device plugin socket: {e}");
+                                state.record_watcher_error(&e);
+                                match policy {
+                                    WatchErrorPolicy::Restart => RestartReason::WatchError,
+                                    WatchErrorPolicy::Ignore => return,
+                                    WatchErrorPolicy::Rewatch => {
+                                        let _ = rewatch_tx.send(());
+                                        return;
+                                    }
+                                }
+                            }
+                        };
+                        tx.send_replace(Some(reason));
+                    }
+                })
+                .context(ShutdownReason::WatchFailed)?;
+
+                watcher
+                    .watch(&socket_path, RecursiveMode::NonRecursive)
+                    .context(ShutdownReason::WatchFailed)?;
+
+                // owns the watcher until the server stops
+                Some(spawn({
+                    let socket_path = socket_path.clone();
+                    let tx = tx.clone();
+                    async move {
+                        while rewatch_rx.recv().await.is_some() {
+                            warn!("re-establishing device plugin socket watch...");
+                            let _ = watcher.unwatch(&socket_path);
+                            if let Err(e) = watcher.watch(&socket_path, RecursiveMode::NonRecursive)
+                            {
+                                error!("failed to re-watch device plugin socket: {e}");
+                                tx.send_replace(Some(RestartReason::WatchError));
+                            }
+                        }
+                    }
+                }))
+            } else {
+                None
+            };
+
+            let started = Instant::now();
+            let res = self.serve(uds, rx, None).await;
+            if let Some(rewatch) = rewatch {
+                rewatch.abort();
+            }
+            if let Err(e) = self.remove_socket(&socket_path).await {
+                warn!("failed to remove {socket_path:?}: {e:#}");
+            }
+            res?;
+
+            // kubelet restarted, its registration service is gone too
+            *self.registration_client.lock().unwrap() = None;
+            self.state.inc_restarts();
+            let reason = tx.borrow().unwrap_or(RestartReason::Other);
+            DP::on_reconnect(reason).await;
+
+            // only back off further on restarts in quick succession
+            if started.elapsed() >= max_backoff {
+                backoff = initial_backoff;
+            }
+            if !backoff.is_zero() {
+                info!("restarting in {backoff:?}...");
+                sleep(backoff).await;
+            }
+            backoff = (backoff * 2).min(max_backoff);
+        }
+    }
+
+    /// Wait for `dir_path` to exist again if it was removed, e.g. kubelet
+    /// wiping it during an upgrade.
+    async fn wait_for_dir(&self) {
+        if self.dir_path.is_dir() {
+            return;
+        }
+        warn!(
+            "{:?} disappeared, waiting for it to reappear...",
+            self.dir_path
+        );
+        self.state
+            .push_event(format!("{:?} disappeared", self.dir_path));
+        while !self.dir_path.is_dir() {
+            sleep(DIR_POLL_INTERVAL).await;
+        }
+        info!("{:?} reappeared!", self.dir_path);
+    }
+
+    /// Serve on a listener bound by the caller instead of binding
+    /// `dir_path/socket_name`, e.g. one passed by systemd socket activation
+    /// (see `std::os::unix::io::FromRawFd`), then register to kubelet.
+    ///
+    /// The listener must still be bound to `dir_path/socket_name`, as that's
+    /// the endpoint registered to kubelet. The caller owns the socket: it's
+    /// never removed nor rebound, so when kubelet restarts and wipes its
+    /// directory, the caller has to recreate it (systemd does on restart).
+    /// The plugin re-registers whenever `kubelet.sock` is recreated. Returns
+    /// once the server stops.
+    pub async fn run_with_listener(
+        mut self,
+        listener: std::os::unix::net::UnixListener,
+    ) -> anyhow::Result<()> {
+        self.prepare().context(ShutdownReason::InvalidDirectory)?;
+        self.resolve_dir()
+            .context(ShutdownReason::InvalidDirectory)?;
+        preflight::<DP>().await?;
+        listener.set_nonblocking(true)?;
+        let uds = UnixListener::from_std(listener)?;
+
+        let (tx, rx) = watch::channel(());
+        let kubelet_sock = self.dir_path.join(KUBELET_SOCK);
+        let state = self.state.clone();
+        let mut watcher = recommended_watcher(move |res: notify::Result<Event>| match res {
+            Ok(event) if event.kind.is_create() && event.paths.contains(&kubelet_sock) => {
+                tx.send_replace(());
+            }
+            Ok(_) => {}
+            Err(e) => {
+                error!("failed to watch kubelet socket: {e}");
+                state.record_watcher_error(&e);
+            }
+        })
+        .context(ShutdownReason::WatchFailed)?;
+        watcher
+            .watch(&self.dir_path, RecursiveMode::NonRecursive)
+            .context(ShutdownReason::WatchFailed)?;
+
+        // the socket isn't ours to restart on
+        let (_restart_tx, restart_rx) = watch::channel(None);
+        let res = self.serve(uds, restart_rx, Some(rx)).await;
+        self.stopped(res)
+    }
+
+    /// Log why the server stopped, attaching a `ShutdownReport` to errors.
+    fn stopped(&self, res: anyhow::Result<()>) -> anyhow::Result<()> {
+        let report = ShutdownReport {
+            reason: res
+                .as_ref()
+                .err()
+                .and_then(|e| e.downcast_ref::<ShutdownReason>())
+                .copied(),
+            restarts: self.state.restarts(),
+            registered: self.state.registered(),
+        };
+        match res {
+            Ok(()) => {
+                info!(
+                    restarts = report.restarts,
+                    registered = report.registered,
+                    "plugin server stopped!"
+                );
+                Ok(())
+            }
+            Err(e) => {
+                error!(
+                    reason = ?report.reason,
+                    restarts = report.restarts,
+                    registered = report.registered,
+                    "plugin server stopped: {e:#}"
+                );
+                Err(e.context(report))
             }
+        }
+    }
+
+    /// Resolve relative and symlinked paths in `dir_path` once, so the watcher
+    /// and the socket cleanup always operate on the same file. `dir_path` must
+    /// exist.
+    fn resolve_dir(&mut self) -> anyhow::Result<()> {
+        self.dir_path = fs::canonicalize(&self.dir_path)
+            .with_context(|| format!("unable to resolve device plugin dir {:?}", self.dir_path))?;
+        Ok(())
+    }
 
-            let uds = UnixListener::bind(socket_path.clone())?;
+    /// Log the plugin version, check `dir_path` is a known kubelet directory
+    /// and start the background tasks enabled by the builder.
+    fn prepare(&mut self) -> anyhow::Result<()> {
+        let version = self.state.version();
+        info!(
+            "starting {} device plugin (generic-device-plugin {}, build {})",
+            DP::RESOURCE_NAME,
+            version.crate_version,
+            version.build.as_deref().unwrap_or("unknown")
+        );
 
-            let (tx, mut rx) = watch::channel(());
-            let mut watcher = recommended_watcher(move |res| {
-                if let Err(e) = res {
-                    error!("failed to watch device plugin socket: {e}")
+        if !is_known_dir(&self.dir_path) {
+            if self.strict_dir_path {
+                bail!(
+                    "{:?} is not a known kubelet device plugin directory {DEVICE_PLUGIN_PATHS:?}",
+                    self.dir_path
+                );
+            }
+            warn!(
+                "{:?} is not a known kubelet device plugin directory {DEVICE_PLUGIN_PATHS:?}, \
+                 make sure kubelet is configured to scan it",
+                self.dir_path
+            );
+        }
+
+        #[cfg(feature = "status")]
+        if let Some(addr) = self.status_addr {
+            let state = self.state.clone();
+            spawn(async move {
+                if let Err(e) = status::serve(addr, state).await {
+                    error!("status server failed: {e}");
                 }
-                let _ = tx.send(());
-            })?;
+            });
+        }
 
-            watcher.watch(&socket_path, RecursiveMode::NonRecursive)?;
+        if let Some(spec) = self.cdi_spec.take() {
+            spawn(cdi::sync::<DP>(spec, self.state.clone()));
+        }
+
+        if self.state.has_state_file() {
+            spawn(snapshot::sync(self.state.clone()));
+        }
+
+        if self.rescan_on_sighup {
+            match signal(SignalKind::hangup()) {
+                Ok(mut hangup) => {
+                    let state = self.state.clone();
+                    spawn(async move {
+                        while hangup.recv().await.is_some() {
+                            info!("SIGHUP received, rescanning devices...");
+                            state.push_event("rescan requested by SIGHUP");
+                            state.request_rescan();
+                        }
+                    });
+                }
+                Err(e) => error!("failed to handle SIGHUP, rescans won't be triggered: {e}"),
+            }
+        }
 
-            let handle = spawn(
-                Server::builder()
-                    .add_service(DevicePluginServer::new(DP::default()))
-                    .serve_with_incoming_shutdown(UnixListenerStream::new(uds), async move {
+        Ok(())
+    }
+
+    /// Start the device plugin server on `uds` and register to kubelet, until
+    /// `restart_rx` reports a restart.
+    async fn serve(
+        &self,
+        uds: UnixListener,
+        restart_rx: watch::Receiver<Option<RestartReason>>,
+        mut kubelet_restart_rx: Option<watch::Receiver<()>>,
+    ) -> anyhow::Result<()> {
+        let socket_path = self.dir_path.join(&self.socket_name);
+        self.check_endpoint(&uds)
+            .context(ShutdownReason::RegistrationFailed)?;
+        let mut rx = restart_rx.clone();
+
+        let mut handle = spawn(
+            Server::builder()
+                .layer(option_layer(
+                    self.concurrency_limit.map(ConcurrencyLimitLayer::new),
+                ))
+                .add_service(DevicePluginServer::new(
+                    GenericDevicePluginService::<DP>::new(
+                        self.service_config.clone(),
+                        self.state.clone(),
+                    ),
+                ))
+                .serve_with_incoming_shutdown(UnixListenerStream::new(uds), {
+                    let state = self.state.clone();
+                    async move {
                         let _ = rx.changed().await;
-                        warn!("socket file changed, restarting server...")
-                    }),
-            );
-            info!("plugin server started on {socket_path:?}!");
+                        let reason = rx.borrow().unwrap_or(RestartReason::Other);
+                        warn!("{reason}, restarting server...");
+                        state.set_phase(ServerPhase::Restarting);
+                        state.push_event(format!("{reason}, restarting server"));
+                    }
+                }),
+        );
+        info!("plugin server started on {socket_path:?}!");
+        self.state.set_phase(ServerPhase::Serving);
+        self.state
+            .push_event(format!("plugin server started on {socket_path:?}"));
+
+        if let Err(e) = self.register().await {
+            handle.abort();
+            return Err(e.context(ShutdownReason::RegistrationFailed));
+        }
+        info!("plugin registered!");
+        self.state.set_phase(ServerPhase::Registered);
+        self.state.push_event("plugin registered");
+
+        let mut ticker = self.reregister_interval.map(|period| {
+            let mut ticker = interval_at(Instant::now() + period, period);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            ticker
+        });
+        // bound how long in-flight streams may delay a restart
+        let drain_timeout = {
+            let mut rx = restart_rx.clone();
+            let grace = self.shutdown_grace;
+            async move {
+                if rx.changed().await.is_err() {
+                    pending::<()>().await;
+                }
+                sleep(grace).await;
+            }
+        };
+        tokio::pin!(drain_timeout);
+        loop {
+            select! {
+                _ = &mut handle => break,
+                _ = &mut drain_timeout => {
+                    warn!("server didn't stop within {:?}, aborting it", self.shutdown_grace);
+                    handle.abort();
+                    break;
+                }
+                status = self.state.discovery_fatal() => {
+                    handle.abort();
+                    return Err(anyhow::Error::from(ServerError::DiscoveryFatal(status))
+                        .context(ShutdownReason::DiscoveryFatal));
+                }
+                _ = tick(&mut ticker) => {
+                    // server is shutting down for a restart, which registers
+                    // again anyway
+                    if restart_rx.has_changed().unwrap_or(true) {
+                        continue;
+                    }
+                    match self.register().await {
+                        Ok(()) => info!("plugin re-registered!"),
+                        Err(e) => warn!("failed to re-register plugin: {e}"),
+                    }
+                }
+                _ = changed(&mut kubelet_restart_rx) => {
+                    warn!("kubelet restarted, re-registering...");
+                    self.state.push_event("kubelet restarted, re-registering");
+                    *self.registration_client.lock().unwrap() = None;
+                    match self.register().await {
+                        Ok(()) => info!("plugin re-registered!"),
+                        Err(e) => warn!("failed to re-register plugin: {e}"),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Make sure kubelet can connect back to the registered endpoint, i.e.
+    /// `uds` is bound to a file named `socket_name`.
+    fn check_endpoint(&self, uds: &UnixListener) -> anyhow::Result<()> {
+        let bound = uds.local_addr()?.as_pathname().map(Path::to_path_buf);
+        let bound_name = bound.as_deref().and_then(Path::file_name);
+        if bound_name != Some(OsStr::new(&self.socket_name)) {
+            return Err(ServerError::EndpointMismatch {
+                bound,
+                endpoint: self.socket_name.clone(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    async fn bind(&self, socket_path: &Path) -> anyhow::Result<UnixListener> {
+        let path = socket_path.to_path_buf();
+        let uds = self
+            .fs_op(move || std::os::unix::net::UnixListener::bind(path))
+            .await?
+            .map_err(|e| self.fs_error(e))?;
+        uds.set_nonblocking(true)?;
+        Ok(UnixListener::from_std(uds)?)
+    }
+
+    /// Run `f` on the device plugin directory. With `bind_timeout`, it runs
+    /// off the runtime and fails with `ServerError::BindTimeout` if it takes
+    /// longer, as it may block on the filesystem.
+    async fn fs_op<T: Send + 'static>(
+        &self,
+        f: impl FnOnce() -> io::Result<T> + Send + 'static,
+    ) -> anyhow::Result<io::Result<T>> {
+        let Some(bind_timeout) = self.bind_timeout else {
+            return Ok(f());
+        };
+        Ok(timeout(bind_timeout, spawn_blocking(f))
+            .await
+            .map_err(|_| ServerError::BindTimeout(bind_timeout))??)
+    }
+
+    /// Remove a socket file, retrying a few times on transient errors (e.g.
+    /// `EBUSY`). A missing file counts as removed.
+    async fn remove_socket(&self, path: &Path) -> anyhow::Result<()> {
+        let mut attempts = 1;
+        loop {
+            let removed = {
+                let path = path.to_path_buf();
+                self.fs_op(move || fs::remove_file(path)).await?
+            };
+            match removed {
+                Ok(()) => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+                Err(e)
+                    if attempts < REMOVE_ATTEMPTS
+                        && e.kind() != ErrorKind::PermissionDenied
+                        && e.kind() != ErrorKind::ReadOnlyFilesystem =>
+                {
+                    warn!("failed to remove {path:?}: {e}, retrying...");
+                    attempts += 1;
+                    sleep(REMOVE_RETRY_DELAY).await;
+                }
+                Err(e) => return Err(self.fs_error(e)),
+            }
+        }
+    }
 
-            self.register().await?;
-            info!("plugin registered!");
+    /// Surface a read-only device plugin directory as
+    /// `ServerError::ReadOnlyDirectory` instead of an opaque io error.
+    fn fs_error(&self, e: io::Error) -> anyhow::Error {
+        if e.kind() == ErrorKind::ReadOnlyFilesystem {
+            ServerError::ReadOnlyDirectory(self.dir_path.clone()).into()
+        } else {
+            e.into()
+        }
+    }
 
-            let _ = handle.await;
-            let _ = fs::remove_file(&socket_path);
+    fn registration_uri_endpoint(&self) -> anyhow::Result<Endpoint> {
+        let uri = &self.registration_uri;
+        if uri.scheme_str() != Some("http") || uri.authority().is_none() {
+            bail!("invalid registration URI {uri:?}, expected http://<authority>");
         }
+        Ok(Endpoint::from(uri.clone()))
     }
 
+    async fn connect_registration(&self) -> anyhow::Result<Channel> {
+        let channel = match &self.registration_endpoint {
+            Some(endpoint) => endpoint()?.connect().await?,
+            None => {
+                self.registration_uri_endpoint()?
+                    .connect_with_connector(unix_connector(self.dir_path.join(KUBELET_SOCK)))
+                    .await?
+            }
+        };
+        Ok(channel)
+    }
+
+    /// Register to kubelet, reusing the connection of previous registrations
+    /// until it fails or kubelet restarts.
     async fn register(&self) -> anyhow::Result<()> {
-        let register_client_socket_path = self.dir_path.join(KUBELET_SOCK);
-        RegistrationClient::new(
-            Endpoint::try_from("http://[::]:50051")?
-                .connect_with_connector(service_fn(move |_: Uri| {
-                    UnixStream::connect(register_client_socket_path.clone())
-                }))
-                .await?,
-        )
-        .register(Request::new(RegisterRequest {
-            endpoint: self.socket_name.clone(),
-            resource_name: DP::RESOURCE_NAME.to_string(),
-            version: VERSION.to_string(),
-            options: Some(DevicePluginOptions {
-                pre_start_required: DP::PRE_START_REQUIRED,
-                get_preferred_allocation_available: DP::GET_PREFERRED_ALLOCATION_AVAILABLE,
-            }),
-        }))
-        .await?;
+        let resource_name = self
+            .resource_name
+            .clone()
+            .unwrap_or_else(|| DP::RESOURCE_NAME.to_string());
+        check_resource_name(&resource_name)
+            .with_context(|| format!("invalid resource name {resource_name:?}"))?;
+
+        let cached = self.registration_client.lock().unwrap().clone();
+        let mut client = match cached {
+            Some(client) => client,
+            None => RegistrationClient::new(self.connect_registration().await?),
+        };
+        let res = client
+            .register(Request::new(RegisterRequest {
+                endpoint: self.socket_name.clone(),
+                resource_name,
+                version: VERSION.to_string(),
+                options: Some(DP::device_plugin_options()),
+            }))
+            .await;
+        *self.registration_client.lock().unwrap() = res.is_ok().then_some(client);
+        if let Err(status) = &res {
+            if let Some(supported) = supported_versions(status) {
+                error!("kubelet doesn't support {VERSION}, only {supported:?}");
+                return Err(ServerError::VersionMismatch {
+                    requested: VERSION.to_string(),
+                    supported,
+                }
+                .into());
+            }
+        }
+        res?;
         Ok(())
     }
 }
+
+/// Versions kubelet supports if it rejected the registration for its version.
+///
+/// kubelet reports it as `requested API version "v1beta2" is not supported by
+/// kubelet. Supported versions are ["v1beta1"]`.
+fn supported_versions(status: &Status) -> Option<Vec<String>> {
+    let message = status.message();
+    if !message.contains("is not supported by kubelet") {
+        return None;
+    }
+    let supported = message
+        .split_once("Supported versions are")
+        .map(|(_, versions)| {
+            versions
+                .split('"')
+                .skip(1)
+                .step_by(2)
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+    Some(supported)
+}
+
+/// Run `DP::preflight`, before anything is served.
+async fn preflight<DP: GenericDevicePlugin>() -> anyhow::Result<()> {
+    DP::preflight()
+        .await
+        .map_err(|status| anyhow!("{status}"))
+        .context(ShutdownReason::PreflightFailed)?;
+    info!("preflight check passed!");
+    Ok(())
+}
+
+/// Connector dialing the unix socket at `path` whatever the URI, for
+/// `Endpoint::connect_with_connector`: gRPC clients of kubelet's sockets
+/// (registration, pod resources) or of a plugin socket in tests.
+pub fn unix_connector(
+    path: PathBuf,
+) -> impl Service<
+    Uri,
+    Response = UnixStream,
+    Error = io::Error,
+    Future = impl Future<Output = io::Result<UnixStream>> + Send,
+> + Clone
+       + Send
+       + 'static {
+    service_fn(move |_: Uri| UnixStream::connect(path.clone()))
+}
+
+/// Check `name` is a valid extended resource name (`domain/name`), as kubelet
+/// accepts registrations of invalid ones that then never schedule.
+fn check_resource_name(name: &str) -> anyhow::Result<()> {
+    let Some((domain, name)) = name.split_once('/') else {
+        bail!("must be of the form domain/name, e.g. example.com/gpu");
+    };
+    if domain == "kubernetes.io" || domain.ends_with(".kubernetes.io") {
+        bail!("the kubernetes.io domain is reserved");
+    }
+    let label_ok = |label: &str| {
+        !label.is_empty()
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    };
+    if domain.len() > 253 || !domain.split('.').all(label_ok) {
+        bail!("domain {domain:?} must be a lowercase DNS subdomain");
+    }
+    let name_ok = name.len() <= 63
+        && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name.ends_with(|c: char| c.is_ascii_alphanumeric())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !name_ok {
+        bail!(
+            "name {name:?} must be at most 63 alphanumeric, '-', '_' or '.' characters, starting \
+             and ending with an alphanumeric one"
+        );
+    }
+    Ok(())
+}
+
+/// Complete when `rx` changes, never complete if there's none or its sender
+/// is gone.
+async fn changed(rx: &mut Option<watch::Receiver<()>>) {
+    match rx {
+        Some(rx) => {
+            if rx.changed().await.is_err() {
+                pending().await
+            }
+        }
+        None => pending().await,
+    }
+}
+
+/// Tick `ticker` if any, never complete otherwise.
+async fn tick(ticker: &mut Option<Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use tokio::sync::mpsc;
+    use tonic::{Request, Response, Status};
+
+    use super::*;
+    use crate::pb::{
+        device_plugin_client::DevicePluginClient,
+        registration_server::{Registration, RegistrationServer},
+        Empty, RegisterRequest,
+    };
+
+    thread_local! {
+        /// devices `TestPlugin` discovers, set by each test
+        pub(crate) static DEVICES: RefCell<Vec<Device>> = RefCell::default();
+    }
+
+    #[derive(Default)]
+    pub(crate) struct TestPlugin;
+
+    #[async_trait::async_trait]
+    impl GenericDevicePlugin for TestPlugin {
+        const PRE_START_REQUIRED: bool = false;
+        const GET_PREFERRED_ALLOCATION_AVAILABLE: bool = false;
+        const RESOURCE_NAME: &'static str = "example.com/test";
+        const DEVICE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+        const DISCOVERY_BATCH_SIZE: usize = 2;
+
+        async fn get_devices() -> Result<Vec<Device>, Status> {
+            Ok(DEVICES.with(|x| x.borrow().clone()))
+        }
+
+        fn public_device_id(internal_id: &str) -> String {
+            internal_id.replace("internal-", "public-")
+        }
+
+        async fn get_container_preferred_allocation(
+            _available_device_ids: Vec<String>,
+            _must_include_device_ids: Vec<String>,
+            _allocation_size: i32,
+        ) -> Result<ContainerPreferredAllocationResponse, Status> {
+            Err(Status::unimplemented(
+                "GET_PREFERRED_ALLOCATION_AVAILABLE = false",
+            ))
+        }
+
+        async fn container_allocate(
+            device_ids: Vec<String>,
+        ) -> Result<ContainerAllocateResponse, Status> {
+            if device_ids.iter().any(|id| id == "broken") {
+                return Err(Status::internal("broken device"));
+            }
+            Ok(ContainerAllocateResponse::default())
+        }
+
+        async fn pre_start_container(_device_ids: Vec<String>) -> Result<(), Status> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn read_only_directory() {
+        let server = GenericDevicePluginServer::<TestPlugin>::new(
+            PathBuf::from("/var/lib/kubelet/device-plugins"),
+            "test.sock".to_string(),
+        );
+
+        let e = server.fs_error(io::Error::from_raw_os_error(libc::EROFS));
+        assert!(matches!(
+            e.downcast_ref::<ServerError>(),
+            Some(ServerError::ReadOnlyDirectory(_))
+        ));
+        let e = server.fs_error(io::Error::from(ErrorKind::PermissionDenied));
+        assert!(e.downcast_ref::<ServerError>().is_none());
+    }
+
+    /// kubelet registration service, forwarding every request
+    struct Kubelet(mpsc::UnboundedSender<RegisterRequest>);
+
+    #[async_trait::async_trait]
+    impl Registration for Kubelet {
+        async fn register(
+            &self,
+            request: Request<RegisterRequest>,
+        ) -> Result<Response<Empty>, Status> {
+            let _ = self.0.send(request.into_inner());
+            Ok(Response::new(Empty {}))
+        }
+    }
+
+    #[tokio::test]
+    async fn reregisters_after_socket_removal() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, mut registrations) = mpsc::unbounded_channel();
+        let kubelet = UnixListener::bind(dir.path().join(KUBELET_SOCK)).unwrap();
+        spawn(
+            Server::builder()
+                .add_service(RegistrationServer::new(Kubelet(tx)))
+                .serve_with_incoming(UnixListenerStream::new(kubelet)),
+        );
+        let socket_path = dir.path().join("test.sock");
+        let server = GenericDevicePluginServer::<TestPlugin>::new(
+            dir.path().to_path_buf(),
+            "test.sock".to_string(),
+        )
+        .restart_backoff(Duration::ZERO, Duration::ZERO);
+        let run = spawn(server.run());
+
+        for _ in 0..2 {
+            let registered = timeout(Duration::from_secs(10), registrations.recv())
+                .await
+                .expect("not registered")
+                .unwrap();
+            assert_eq!(registered.endpoint, "test.sock");
+            assert_eq!(registered.resource_name, TestPlugin::RESOURCE_NAME);
+
+            // serving on the (re)bound socket
+            let channel = Endpoint::from_static(REGISTRATION_URI)
+                .connect_with_connector(unix_connector(socket_path.clone()))
+                .await
+                .unwrap();
+            DevicePluginClient::new(channel)
+                .get_device_plugin_options(Empty {})
+                .await
+                .unwrap();
+
+            // as kubelet does on restart
+            fs::remove_file(&socket_path).unwrap();
+        }
+        assert!(!run.is_finished());
+        run.abort();
+    }
+
+    #[test]
+    fn registration_uri() {
+        let server = || {
+            GenericDevicePluginServer::<TestPlugin>::new(
+                PathBuf::from("/var/lib/kubelet/device-plugins"),
+                "test.sock".to_string(),
+            )
+        };
+        let endpoint = server().registration_uri_endpoint().unwrap();
+        assert_eq!(endpoint.uri(), &Uri::from_static(REGISTRATION_URI));
+
+        let server = |uri| server().registration_uri(Uri::from_static(uri));
+        let endpoint = server("http://localhost")
+            .registration_uri_endpoint()
+            .unwrap();
+        assert_eq!(endpoint.uri(), &Uri::from_static("http://localhost"));
+        for uri in ["/kubelet.sock", "https://localhost"] {
+            assert!(server(uri).registration_uri_endpoint().is_err(), "{uri}");
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::fmt;
+
+use notify::{
+    event::{ModifyKind, RemoveKind},
+    EventKind,
+};
+
+/// Why the server restarted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartReason {
+    /// the socket file was removed, usually because kubelet restarted
+    SocketRemoved,
+    /// the socket file was renamed
+    SocketRenamed,
+    /// the socket file content or metadata changed
+    SocketModified,
+    /// the socket file was created again
+    SocketCreated,
+    /// watching the socket file failed
+    WatchError,
+    /// any other event on the socket file
+    Other,
+}
+
+impl From<EventKind> for RestartReason {
+    fn from(kind: EventKind) -> Self {
+        match kind {
+            EventKind::Remove(RemoveKind::Any | RemoveKind::File | RemoveKind::Other) => {
+                Self::SocketRemoved
+            }
+            EventKind::Modify(ModifyKind::Name(_)) => Self::SocketRenamed,
+            EventKind::Modify(_) => Self::SocketModified,
+            EventKind::Create(_) => Self::SocketCreated,
+            _ => Self::Other,
+        }
+    }
+}
+
+impl fmt::Display for RestartReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::SocketRemoved => "socket removed, kubelet restart detected",
+            Self::SocketRenamed => "socket renamed",
+            Self::SocketModified => "socket modified",
+            Self::SocketCreated => "socket created",
+            Self::WatchError => "failed to watch socket",
+            Self::Other => "socket file changed",
+        })
+    }
+}
+
+/// What the server does when watching its socket file fails, see
+/// `GenericDevicePluginServer::watch_error_policy`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WatchErrorPolicy {
+    /// restart the server, as for a socket change
+    #[default]
+    Restart,
+    /// log the error and keep serving
+    Ignore,
+    /// re-establish the watch without restarting, restarting only if that
+    /// fails
+    Rewatch,
+}
+
+/// Why `run` stopped, attached as context to the error it returns:
+/// `err.downcast_ref::<ShutdownReason>()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownReason {
+    /// the device plugin directory can't be used
+    InvalidDirectory,
+    /// `GenericDevicePlugin::preflight` failed
+    PreflightFailed,
+    /// the socket is used by another server or can't be cleaned up
+    SocketUnavailable,
+    /// binding the socket failed
+    BindFailed,
+    /// watching the socket failed
+    WatchFailed,
+    /// registering to kubelet failed
+    RegistrationFailed,
+    /// `get_devices` failed fatally
+    DiscoveryFatal,
+}
+
+impl fmt::Display for ShutdownReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::InvalidDirectory => "invalid device plugin directory",
+            Self::PreflightFailed => "plugin preflight check failed",
+            Self::SocketUnavailable => "device plugin socket unavailable",
+            Self::BindFailed => "failed to bind device plugin socket",
+            Self::WatchFailed => "failed to watch device plugin socket",
+            Self::RegistrationFailed => "failed to register to kubelet",
+            Self::DiscoveryFatal => "device discovery failed fatally",
+        })
+    }
+}
+
+/// Summary of a stopped server, logged and attached as context to the error
+/// `run` returns: `err.downcast_ref::<ShutdownReport>()`.
+///
+/// v1beta1 has no deregistration: kubelet notices the plugin is gone once its
+/// socket stops accepting connections, `run` also removes it once serving
+/// stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// `None` for errors without a known reason
+    pub reason: Option<ShutdownReason>,
+    /// how many times the server restarted before stopping
+    pub restarts: u64,
+    /// whether the plugin was registered to kubelet when it stopped
+    pub registered: bool,
+}
+
+impl fmt::Display for ShutdownReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.reason {
+            Some(reason) => write!(f, "plugin server stopped ({reason})")?,
+            None => f.write_str("plugin server stopped")?,
+        }
+        write!(
+            f,
+            " after {} restarts, {}",
+            self.restarts,
+            if self.registered {
+                "registered"
+            } else {
+                "not registered"
+            }
+        )
+    }
+}
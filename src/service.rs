@@ -1,24 +1,301 @@
-use std::{pin::Pin, time::Duration};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    future::{pending, Future},
+    marker::PhantomData,
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
 
-use tokio::{sync::mpsc, time::sleep};
-use tokio_stream::wrappers::ReceiverStream;
-use tonic::{codegen::tokio_stream::Stream, Request, Response, Status};
-use tracing::{error, info};
+use prost::Message;
+use tokio::{
+    sync::{
+        mpsc::{self, error::SendError},
+        watch, Semaphore,
+    },
+    task::JoinSet,
+    time::{sleep, timeout_at, Instant},
+};
+use tokio_stream::{
+    wrappers::{ReceiverStream, WatchStream},
+    StreamExt,
+};
+use tonic::{codegen::tokio_stream::Stream, metadata::MetadataMap, Request, Response, Status};
+use tracing::{debug, error, info, trace, warn};
 
-use super::pb::{device_plugin_server::DevicePlugin, *};
+use super::{
+    discovery::DeviceFilter,
+    lifecycle::RestartReason,
+    limit::TokenBucket,
+    pb::{device_plugin_server::DevicePlugin, *},
+    state::{CooldownGuard, PluginState},
+    types::{DevicePermissions, DeviceSpecTemplate, UNHEALTHY},
+    GenericDevicePluginServer,
+};
 
+/// How the server reacts to a `get_devices` error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiscoveryOutcome {
+    /// keep polling, the error is sent to kubelet
+    Transient,
+    /// stop polling and fail `run` with `ServerError::DiscoveryFatal`
+    Fatal,
+}
+
+/// What a ListAndWatch stream sends when `get_devices` fails, see
+/// `GenericDevicePluginServer::stream_error_policy`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StreamErrorPolicy {
+    /// send the error, ending the stream: kubelet drops the connection and
+    /// marks the devices unhealthy until the plugin registers again
+    #[default]
+    FailStream,
+    /// send nothing, kubelet keeps the last good list
+    HoldLastGood,
+    /// send an empty list, kubelet removes every device
+    EmptyOnError,
+}
+
+/// Asynchronous health change of a device, see
+/// `GenericDevicePlugin::health_updates`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthUpdate {
+    /// device id, as returned by `get_devices`
+    pub id: String,
+    pub health: String,
+}
+
+pub type HealthUpdates = Pin<Box<dyn Stream<Item = HealthUpdate> + Send>>;
+/// see `GenericDevicePlugin::rescan_requests`
+pub type RescanRequests = Pin<Box<dyn Stream<Item = ()> + Send>>;
+/// see `GenericDevicePlugin::device_stream`
+pub type DeviceStream = Pin<Box<dyn Stream<Item = Result<Device, Status>> + Send>>;
+
+/// A device plugin, implemented as associated functions and constants: the
+/// framework never holds an instance across calls.
+///
+/// State shared by the methods (a driver handle, parsed configuration...) is
+/// built once and kept in a `static`, e.g. a `once_cell::sync::OnceCell`
+/// initialized before calling `run`, or lazily on first use with
+/// `once_cell::sync::Lazy`.
+///
+/// Async methods go through `async_trait`, boxing their future: an
+/// allocation per call, negligible next to the gRPC round-trip. Native
+/// `async fn` in traits would avoid it, but needs Rust 1.75 and can't require
+/// the returned futures to be `Send`, which the framework needs to spawn
+/// them, short of spelling every method as `-> impl Future + Send`.
+///
+/// Errors can be built from a `PluginError` (`PluginError::..(msg).into()`) to
+/// get consistent `Status` codes across plugins.
 #[async_trait::async_trait]
 pub trait GenericDevicePlugin: 'static + Sync + Send + Default {
     const PRE_START_REQUIRED: bool;
     const GET_PREFERRED_ALLOCATION_AVAILABLE: bool;
     const RESOURCE_NAME: &'static str;
     const DEVICE_POLL_INTERVAL: Duration;
+    /// Devices no longer returned by `get_devices` are still advertised as
+    /// unhealthy for this long before being removed, to ride out transient
+    /// probe failures. Removed immediately by default.
+    const DEVICE_DISAPPEARANCE_GRACE: Duration = Duration::ZERO;
+
+    /// Reject `allocate` requests for ids missing from the last device list
+    /// advertised to kubelet with `NOT_FOUND`, to catch kubelet/plugin
+    /// desyncs early. Off by default, as plugins may handle dynamic ids.
+    const VALIDATE_ALLOCATE_IDS: bool = false;
 
+    /// Run `device_health` on every discovered device, at most this many at
+    /// once. Skipped by default.
+    const DEVICE_HEALTH_CHECKS: Option<usize> = None;
+
+    /// Synthetic or known device id allocated by the default `preflight`
+    /// before serving. `None` (default) skips the probe.
+    const PREFLIGHT_DEVICE_ID: Option<&'static str> = None;
+
+    /// How many devices `device_stream` yields before the first partial
+    /// advertisement, each next one waits for the list to double.
+    const DISCOVERY_BATCH_SIZE: usize = 1024;
+
+    /// Server for this plugin, shorthand for `GenericDevicePluginServer::new`.
+    fn server(dir_path: PathBuf, socket_name: String) -> GenericDevicePluginServer<Self> {
+        GenericDevicePluginServer::new(dir_path, socket_name)
+    }
+
+    /// Discover the devices, in any order: they're always advertised sorted
+    /// by id.
     async fn get_devices() -> Result<Vec<Device>, Status>;
 
+    /// Options advertised to kubelet on registration and by
+    /// `GetDevicePluginOptions`. Built from `PRE_START_REQUIRED` and
+    /// `GET_PREFERRED_ALLOCATION_AVAILABLE` by default, override it to
+    /// advertise fields those don't cover.
+    fn device_plugin_options() -> DevicePluginOptions {
+        DevicePluginOptions {
+            pre_start_required: Self::PRE_START_REQUIRED,
+            get_preferred_allocation_available: Self::GET_PREFERRED_ALLOCATION_AVAILABLE,
+        }
+    }
+
+    /// Devices yielded one by one, polled instead of `get_devices` when set.
+    /// A new stream is taken for every poll, the first error fails the poll.
+    ///
+    /// kubelet replaces the whole device list on every ListAndWatch response,
+    /// so partial lists are only advertised while nothing is advertised yet,
+    /// i.e. on startup: large fleets become schedulable before discovery
+    /// completes. Later polls are advertised once complete. Partial lists
+    /// only reach ListAndWatch streams, `on_devices_changed` and the
+    /// allocation checks, the rest of the plugin (`current_devices`, change
+    /// subscribers...) waits for the complete list. None by default.
+    fn device_stream() -> Option<DeviceStream> {
+        None
+    }
+
+    /// Health changes reported asynchronously (e.g. hardware interrupts),
+    /// applied on top of the last `get_devices` result and sent right away.
+    /// An update holds until superseded by another one for the same device
+    /// (send `HEALTHY` to clear it) or the device disappears. None by default.
+    fn health_updates() -> Option<HealthUpdates> {
+        None
+    }
+
+    /// Health of a discovered device, e.g. from pinging it, separating which
+    /// devices exist (`get_devices`) from whether they're healthy. Only called
+    /// when `DEVICE_HEALTH_CHECKS` is set, the health returned by
+    /// `get_devices` by default.
+    async fn device_health(device: &Device) -> String {
+        device.health.clone()
+    }
+
+    /// Devices advertised instead when `get_devices` returns none, given the
+    /// last non-empty list and for how long discovery has been empty.
+    ///
+    /// Advertises nothing by default (fail-closed: the node capacity drops to
+    /// zero). Return `last_devices` to keep advertising them (fail-open),
+    /// e.g. only while `empty_for` is below a grace period.
+    fn empty_device_list(_last_devices: &[Device], _empty_for: Duration) -> Vec<Device> {
+        vec![]
+    }
+
+    /// Self-test run once before serving, failing `run` on error, to surface
+    /// e.g. missing permissions at startup rather than on the first pod. By
+    /// default a dry `container_allocate` of `PREFLIGHT_DEVICE_ID`, if set.
+    async fn preflight() -> Result<(), Status> {
+        match Self::PREFLIGHT_DEVICE_ID {
+            Some(id) => Self::container_allocate(vec![id.to_string()])
+                .await
+                .map(drop),
+            None => Ok(()),
+        }
+    }
+
+    /// Called after every server restart caused by a change of its socket
+    /// (usually kubelet restarting), before the new server starts, e.g. to
+    /// flush caches or re-init hardware handles. Does nothing by default.
+    async fn on_reconnect(_reason: RestartReason) {}
+
+    /// Called once whenever the device list goes from non-empty to empty
+    /// (after `DEVICE_DISAPPEARANCE_GRACE` and `empty_device_list`), e.g. to
+    /// alert on pulled hardware or a crashed driver. Runs in its own task, so
+    /// it doesn't delay discovery. Does nothing by default.
+    async fn on_devices_lost() {}
+
+    /// Called with the device list advertised to kubelet (public ids)
+    /// whenever it changed, e.g. to label the node with device capabilities
+    /// through the Kubernetes API. Calls run one at a time in their own task,
+    /// intermediate lists are skipped if a call is slower than discovery.
+    /// Does nothing by default.
+    async fn on_devices_changed(_devices: &[Device]) {}
+
+    /// Every item polls `get_devices` right away instead of waiting for the
+    /// rest of `DEVICE_POLL_INTERVAL`, e.g. when a configuration file changed.
+    /// None by default.
+    fn rescan_requests() -> Option<RescanRequests> {
+        None
+    }
+
+    /// Classify a `get_devices` error. Every error is transient by default.
+    fn discovery_outcome(_status: &Status) -> DiscoveryOutcome {
+        DiscoveryOutcome::Transient
+    }
+
+    /// Device id advertised to kubelet for an id returned by `get_devices`.
+    /// Ids coming back from kubelet are mapped back to the internal ones
+    /// before being passed to the other methods. Identity by default.
+    fn public_device_id(internal_id: &str) -> String {
+        internal_id.to_string()
+    }
+
+    /// Group of devices that must be allocated together (e.g. a GPU and its
+    /// render node), by id as returned by `get_devices`. Allocations and
+    /// preferred allocations splitting a group of the last discovered list
+    /// are rejected. No groups by default.
+    ///
+    /// kubelet only counts devices, so pods must request whole groups: when
+    /// every group has the same members, advertising one device per group
+    /// exposing all its nodes in `container_allocate` is simpler.
+    fn device_group(_id: &str) -> Option<String> {
+        None
+    }
+
+    /// Host path of the device node behind a device id, used by the default
+    /// `container_allocate`.
+    fn device_host_path(id: &str) -> String {
+        format!("/dev/{id}")
+    }
+
+    /// cgroup device permissions granted on a device, used by the default
+    /// `container_allocate`.
+    fn device_permissions(_id: &str) -> DevicePermissions {
+        DevicePermissions::READ | DevicePermissions::WRITE
+    }
+
+    /// Host mounts a device needs in the container, appended by the framework
+    /// to the `container_allocate` response for every allocated device. Mounts
+    /// shared by several devices are only added once. None by default.
+    async fn mounts_for_device(_id: &str) -> Vec<Mount> {
+        vec![]
+    }
+
+    /// Device spec pattern used by the default `container_allocate` instead of
+    /// `device_host_path` and `device_permissions`. None by default.
+    fn device_spec_template() -> Option<DeviceSpecTemplate> {
+        None
+    }
+
+    /// By default, every device is expanded from `device_spec_template` if
+    /// any, or else exposed at its `device_host_path` inside the container as
+    /// well, with its `device_permissions`.
     async fn container_allocate(
         device_ids: Vec<String>,
-    ) -> Result<ContainerAllocateResponse, Status>;
+    ) -> Result<ContainerAllocateResponse, Status> {
+        if let Some(template) = Self::device_spec_template() {
+            let devices = device_ids
+                .iter()
+                .enumerate()
+                .map(|(index, id)| template.expand(index, id))
+                .collect::<Result<_, _>>()?;
+            return Ok(ContainerAllocateResponse {
+                devices,
+                ..Default::default()
+            });
+        }
+
+        let devices = device_ids
+            .iter()
+            .map(|id| {
+                let path = Self::device_host_path(id);
+                DeviceSpec::new(path.clone(), path, Self::device_permissions(id))
+            })
+            .collect();
+
+        Ok(ContainerAllocateResponse {
+            devices,
+            ..Default::default()
+        })
+    }
 
     async fn get_container_preferred_allocation(
         available_device_ids: Vec<String>,
@@ -26,21 +303,724 @@ pub trait GenericDevicePlugin: 'static + Sync + Send + Default {
         allocation_size: i32,
     ) -> Result<ContainerPreferredAllocationResponse, Status>;
 
-    async fn pre_start_container(device_ids: Vec<String>) -> Result<(), Status>;
+    /// Only called by kubelet when `PRE_START_REQUIRED` is set. No-op by
+    /// default, warning once if `PRE_START_REQUIRED` is set anyway since
+    /// that's almost certainly a forgotten override.
+    async fn pre_start_container(_device_ids: Vec<String>) -> Result<(), Status> {
+        // shared by every plugin type, hence keyed by resource
+        static WARNED: Mutex<BTreeSet<&str>> = Mutex::new(BTreeSet::new());
+        if Self::PRE_START_REQUIRED && WARNED.lock().unwrap().insert(Self::RESOURCE_NAME) {
+            warn!(
+                "{}: PRE_START_REQUIRED is set but pre_start_container isn't implemented",
+                Self::RESOURCE_NAME
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct ServiceConfig {
+    /// drop intermediate device lists and only keep the latest one if kubelet
+    /// is slow to consume the ListAndWatch stream
+    pub(crate) keep_latest: bool,
+    /// device spec permissions allocate may grant
+    pub(crate) allowed_permissions: Option<DevicePermissions>,
+    /// minimum delay between two allocations of the same device
+    pub(crate) allocate_cooldown: Option<Duration>,
+    pub(crate) stream_error_policy: StreamErrorPolicy,
+    /// shared by every service of the server
+    pub(crate) allocate_rate_limit: Option<Arc<Mutex<TokenBucket>>>,
+    /// max concurrent ListAndWatch streams
+    pub(crate) max_streams: Option<usize>,
+    pub(crate) device_filter: Option<DeviceFilter>,
+    /// deadline of RPCs without a `grpc-timeout` from kubelet
+    pub(crate) rpc_timeout: Option<Duration>,
+    /// reserve allocated devices until they disappear
+    pub(crate) exclusive_allocation: bool,
+}
+
+pub(crate) struct GenericDevicePluginService<DP: GenericDevicePlugin> {
+    config: ServiceConfig,
+    state: Arc<PluginState>,
+    discovery: Arc<Mutex<Weak<DeviceSnapshotSender>>>,
+    /// `GetDevicePluginOptions` is logged once per server start, a service
+    /// being shared by every kubelet connection until the next restart
+    options_logged: AtomicBool,
+    _phantom: PhantomData<DP>,
+}
+
+impl<DP: GenericDevicePlugin> GenericDevicePluginService<DP> {
+    pub(crate) fn new(config: ServiceConfig, state: Arc<PluginState>) -> Self {
+        Self {
+            config,
+            state,
+            discovery: Arc::new(Mutex::new(Weak::new())),
+            options_logged: AtomicBool::new(false),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Subscribe to the shared discovery task, starting it if no stream is
+    /// currently subscribed.
+    fn subscribe_devices(&self) -> watch::Receiver<DeviceSnapshot> {
+        let mut discovery = self.discovery.lock().unwrap();
+        if let Some(tx) = discovery.upgrade() {
+            return tx.subscribe();
+        }
+
+        let (tx, rx) = watch::channel(None);
+        let tx = Arc::new(tx);
+        *discovery = Arc::downgrade(&tx);
+        tokio::spawn(Self::discover(
+            tx,
+            self.discovery.clone(),
+            self.state.clone(),
+            self.config.device_filter.clone(),
+        ));
+        rx
+    }
+
+    /// When a trait method called for `metadata`'s RPC must have completed:
+    /// kubelet's `grpc-timeout` if set, else `rpc_timeout`.
+    fn deadline(&self, metadata: &MetadataMap) -> Option<Instant> {
+        let timeout = grpc_timeout(metadata).or(self.config.rpc_timeout)?;
+        Instant::now().checked_add(timeout)
+    }
+
+    fn internal_device_ids(&self, public_ids: Vec<String>) -> Vec<String> {
+        public_ids
+            .iter()
+            .map(|id| self.state.internal_device_id(id))
+            .collect()
+    }
+
+    /// Allocate each container request in turn, failing on the first error.
+    async fn allocate_containers(
+        &self,
+        container_requests: Vec<ContainerAllocateRequest>,
+        deadline: Option<Instant>,
+    ) -> Result<Vec<ContainerAllocateResponse>, Status> {
+        let mut container_responses = Vec::with_capacity(container_requests.len());
+        // cooldowns only start if every container is allocated
+        let mut cooldowns = vec![];
+        for req in container_requests {
+            if DP::VALIDATE_ALLOCATE_IDS {
+                let unknown: Vec<_> = req
+                    .devices_ids
+                    .iter()
+                    .filter(|id| !self.state.is_advertised(id))
+                    .collect();
+                if !unknown.is_empty() {
+                    return Err(Status::not_found(format!(
+                        "allocate requested unadvertised devices {unknown:?}"
+                    )));
+                }
+            }
+            if let Some(cooldown) = self.config.allocate_cooldown {
+                match self.state.start_cooldown(&req.devices_ids, cooldown) {
+                    Ok(guard) => cooldowns.push(guard),
+                    Err(wait) => {
+                        return Err(Status::unavailable(format!(
+                            "devices {:?} allocated too recently, retry in {wait:?}",
+                            req.devices_ids
+                        )));
+                    }
+                }
+            }
+            let device_ids = self.internal_device_ids(req.devices_ids);
+            check_groups::<DP>(&device_ids, &self.state).map_err(Status::invalid_argument)?;
+            trace!("allocating devices {device_ids:?}");
+            let start = Instant::now();
+            let resp = with_deadline(deadline, DP::container_allocate(device_ids.clone())).await;
+            self.state.observe_container_allocate(start.elapsed());
+            let mut resp = resp?;
+            if let Some(allowed) = self.config.allowed_permissions {
+                for spec in &mut resp.devices {
+                    clamp_permissions(spec, allowed)?;
+                }
+            }
+            for id in &device_ids {
+                for mount in DP::mounts_for_device(id).await {
+                    add_mount(&mut resp.mounts, mount);
+                }
+            }
+            trace!("allocated devices {device_ids:?}: {resp:?}");
+            container_responses.push(resp);
+        }
+        cooldowns.into_iter().for_each(CooldownGuard::keep);
+        Ok(container_responses)
+    }
+
+    /// Poll `get_devices` once for all streams, drop devices rejected by
+    /// `filter`, merge in `health_updates`, and publish the result whenever it
+    /// fails or changes. Stops when the last stream unsubscribed.
+    ///
+    /// All timing goes through `tokio::time`, so the loop can be driven
+    /// deterministically with `tokio::time::pause` and `advance`.
+    async fn discover(
+        tx: Arc<DeviceSnapshotSender>,
+        discovery: Arc<Mutex<Weak<DeviceSnapshotSender>>>,
+        state: Arc<PluginState>,
+        filter: Option<DeviceFilter>,
+    ) {
+        let mut prev_devices = Err(Status::unknown(""));
+        // whether a complete device list was advertised, until then streamed
+        // lists are advertised in batches
+        let mut advertised_once = false;
+        let mut last_seen = HashMap::new();
+        let mut last_devices = vec![];
+        let mut empty_since = None;
+        let mut health_updates = DP::health_updates();
+        let mut rescan_requests = DP::rescan_requests();
+        let mut drained = state.subscribe_drained();
+        let mut rescan = state.subscribe_rescan();
+        let mut had_devices = false;
+        // ends once `tx` drops with the loop
+        tokio::spawn(notify_devices_changed::<DP>(tx.subscribe()));
+        let mut health_overrides = HashMap::new();
+        loop {
+            {
+                let mut discovery = discovery.lock().unwrap();
+                // the notifier's receiver is the only one left
+                if tx.receiver_count() <= 1 {
+                    *discovery = Weak::new();
+                    break;
+                }
+            }
+
+            let polled = match DP::device_stream() {
+                Some(stream) => {
+                    let incremental = !advertised_once && !state.drained();
+                    Self::collect_devices(
+                        stream,
+                        &tx,
+                        &state,
+                        &filter,
+                        &health_overrides,
+                        incremental,
+                    )
+                    .await
+                }
+                None => match DP::get_devices().await {
+                    Ok(devices) => Ok(with_checks::<DP>(devices, &filter).await),
+                    Err(e) => Err(e),
+                },
+            };
+            let polled = polled
+                .map(|devices| with_disappeared::<DP>(devices, &mut last_seen))
+                .map(|devices| {
+                    with_empty_device_list::<DP>(devices, &mut last_devices, &mut empty_since)
+                });
+            if let Ok(devices) = &polled {
+                health_overrides.retain(|id, _| devices.iter().any(|x| &x.id == id));
+                if had_devices && devices.is_empty() {
+                    warn!("all devices lost");
+                    state.push_event("all devices lost");
+                    tokio::spawn(DP::on_devices_lost());
+                }
+                had_devices = !devices.is_empty();
+            }
+
+            let devices_resp = polled
+                .clone()
+                .map(|devices| with_health_overrides(devices, &health_overrides));
+            if Self::publish(&tx, &state, &mut prev_devices, devices_resp) {
+                *discovery.lock().unwrap() = Weak::new();
+                break;
+            }
+            advertised_once |= prev_devices.is_ok();
+
+            let next_poll = sleep(DP::DEVICE_POLL_INTERVAL);
+            tokio::pin!(next_poll);
+            loop {
+                tokio::select! {
+                    _ = &mut next_poll => break,
+                    Some(()) = next_item(&mut rescan_requests) => break,
+                    Ok(()) = drained.changed() => break,
+                    Ok(()) = rescan.changed() => break,
+                    Some(update) = next_item(&mut health_updates) => {
+                        health_overrides.insert(update.id, update.health);
+                        if let Ok(devices) = &polled {
+                            let devices = with_health_overrides(devices.clone(), &health_overrides);
+                            Self::publish(&tx, &state, &mut prev_devices, Ok(devices));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Collect a `device_stream`, filtered and health checked batch by batch.
+    /// If `incremental`, the list is sent to ListAndWatch streams as it
+    /// doubles, keeping the copies linear in the number of devices.
+    async fn collect_devices(
+        mut stream: DeviceStream,
+        tx: &DeviceSnapshotSender,
+        state: &PluginState,
+        filter: &Option<DeviceFilter>,
+        health_overrides: &HashMap<String, String>,
+        incremental: bool,
+    ) -> Result<Vec<Device>, Status> {
+        let batch_size = DP::DISCOVERY_BATCH_SIZE.max(1);
+        let mut devices = vec![];
+        let mut batch = Vec::with_capacity(batch_size);
+        let mut next_partial = batch_size;
+        loop {
+            let device = stream.next().await.transpose()?;
+            let done = device.is_none();
+            batch.extend(device);
+            if batch.len() < batch_size && !done {
+                continue;
+            }
+            devices.extend(with_checks::<DP>(std::mem::take(&mut batch), filter).await);
+            if done {
+                return Ok(devices);
+            }
+            if incremental && devices.len() >= next_partial {
+                next_partial = devices.len() * 2;
+                let partial = with_health_overrides(devices.clone(), health_overrides);
+                let mut device_ids = HashMap::new();
+                let mut partial =
+                    with_public_ids::<DP>(with_valid_topology(partial), &mut device_ids);
+                // kubelet may allocate them before the list completes
+                state.add_advertised_ids(device_ids);
+                partial.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+                tx.send_replace(Some(Ok(partial)));
+            }
+        }
+    }
+
+    /// Publish a discovery result if it failed or changed, returns whether it
+    /// failed fatally.
+    fn publish(
+        tx: &DeviceSnapshotSender,
+        state: &PluginState,
+        prev_devices: &mut Result<Vec<Device>, Status>,
+        devices_resp: Result<Vec<Device>, Status>,
+    ) -> bool {
+        let devices_resp = devices_resp.map(|devices| {
+            let devices = with_valid_topology(devices);
+            state.set_cached_devices(&devices);
+            if state.has_reservations() {
+                let present: HashSet<_> = devices
+                    .iter()
+                    .map(|x| DP::public_device_id(&x.id))
+                    .collect();
+                state.retain_reservations(&present);
+            }
+            // mapped before draining, allocations still need internal ids
+            let mut device_ids = HashMap::new();
+            let devices = with_public_ids::<DP>(devices, &mut device_ids);
+            state.set_device_ids(device_ids);
+            let mut devices = if state.drained() { vec![] } else { devices };
+            // advertised in a stable order, whatever the discovery order
+            devices.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+            devices
+        });
+
+        // if error or changed
+        if let (Ok(devices), Ok(prev)) = (&devices_resp, &*prev_devices) {
+            if same_devices(devices, prev) {
+                return false;
+            }
+        }
+        *prev_devices = devices_resp.clone();
+        let fatal = match &devices_resp {
+            Ok(devices) => {
+                if tracing::enabled!(tracing::Level::DEBUG) {
+                    let list: Vec<_> = devices
+                        .iter()
+                        .map(|x| format!("{} ({})", x.id, x.health))
+                        .collect();
+                    debug!("device list changed: [{}]", list.join(", "));
+                }
+                state.set_devices(devices.clone());
+                None
+            }
+            Err(e) => {
+                error!("failed to get devices: {e}");
+                (DP::discovery_outcome(e) == DiscoveryOutcome::Fatal).then(|| e.clone())
+            }
+        };
+        tx.send_replace(Some(devices_resp));
+        match fatal {
+            Some(status) => {
+                state.set_discovery_fatal(status);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Next item of an optional plugin-provided stream, never completes if there
+/// is none.
+async fn next_item<T>(stream: &mut Option<Pin<Box<dyn Stream<Item = T> + Send>>>) -> Option<T> {
+    match stream {
+        Some(stream) => stream.next().await,
+        None => pending().await,
+    }
+}
+
+/// Max message size kubelet's gRPC client accepts (grpc-go default).
+///
+/// The whole device list goes into a single `ListAndWatchResponse`, a device
+/// with a ~10 bytes id and no topology takes ~25 bytes, putting the practical
+/// ceiling around 150k devices, less with long ids or topology info.
+const KUBELET_MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Timeout of an RPC set by the client in the `grpc-timeout` header: up to 8
+/// digits followed by a unit (`H`, `M`, `S`, `m`, `u` or `n`).
+fn grpc_timeout(metadata: &MetadataMap) -> Option<Duration> {
+    let value = metadata.get("grpc-timeout")?.to_str().ok()?;
+    if value.len() < 2 || value.len() > 9 {
+        return None;
+    }
+    let (amount, unit) = value.split_at(value.len() - 1);
+    let amount: u64 = amount.parse().ok()?;
+    Some(match unit {
+        "H" => Duration::from_secs(amount * 60 * 60),
+        "M" => Duration::from_secs(amount * 60),
+        "S" => Duration::from_secs(amount),
+        "m" => Duration::from_millis(amount),
+        "u" => Duration::from_micros(amount),
+        "n" => Duration::from_nanos(amount),
+        _ => return None,
+    })
+}
+
+/// Run a trait method, failing with `DEADLINE_EXCEEDED` past `deadline`.
+async fn with_deadline<T>(
+    deadline: Option<Instant>,
+    fut: impl Future<Output = Result<T, Status>>,
+) -> Result<T, Status> {
+    match deadline {
+        Some(deadline) => timeout_at(deadline, fut)
+            .await
+            .map_err(|_| Status::deadline_exceeded("request deadline exceeded"))?,
+        None => fut.await,
+    }
+}
+
+/// Refuse a response kubelet would reject for its size, warn when it gets
+/// close to the limit.
+fn check_message_size(resp: ListAndWatchResponse) -> Result<ListAndWatchResponse, Status> {
+    let size = resp.encoded_len();
+    if size > KUBELET_MAX_MESSAGE_SIZE {
+        error!(
+            "device list of {} devices takes {size} bytes, over kubelet's \
+             {KUBELET_MAX_MESSAGE_SIZE} bytes limit",
+            resp.devices.len()
+        );
+        return Err(Status::resource_exhausted(format!(
+            "device list too large: {size} > {KUBELET_MAX_MESSAGE_SIZE} bytes"
+        )));
+    }
+    if size > KUBELET_MAX_MESSAGE_SIZE / 10 * 8 {
+        warn!(
+            "device list of {} devices takes {size} bytes, close to kubelet's \
+             {KUBELET_MAX_MESSAGE_SIZE} bytes limit",
+            resp.devices.len()
+        );
+    }
+    Ok(resp)
+}
+
+/// Check `ids` only contains whole `DP::device_group`s of the last discovered
+/// devices.
+fn check_groups<DP: GenericDevicePlugin>(
+    ids: &[String],
+    state: &PluginState,
+) -> Result<(), String> {
+    let known = state.cached_device_ids();
+    let mut split = vec![];
+    for group in ids.iter().filter_map(|id| DP::device_group(id)) {
+        let whole = known
+            .iter()
+            .filter(|id| DP::device_group(id).as_ref() == Some(&group))
+            .all(|id| ids.contains(id));
+        if !whole && !split.contains(&group) {
+            split.push(group);
+        }
+    }
+    if !split.is_empty() {
+        return Err(format!("allocation splits device groups {split:?}"));
+    }
+    Ok(())
+}
+
+/// Apply `policy` to a discovery result, `None` if nothing should be sent.
+fn with_error_policy(
+    devices_resp: Result<Vec<Device>, Status>,
+    policy: StreamErrorPolicy,
+) -> Option<Result<Vec<Device>, Status>> {
+    match (devices_resp, policy) {
+        (Err(_), StreamErrorPolicy::HoldLastGood) => None,
+        (Err(_), StreamErrorPolicy::EmptyOnError) => Some(Ok(vec![])),
+        (devices_resp, _) => Some(devices_resp),
+    }
+}
+
+/// Call `DP::on_devices_changed` on every device list published on `rx`,
+/// until discovery stops.
+async fn notify_devices_changed<DP: GenericDevicePlugin>(mut rx: watch::Receiver<DeviceSnapshot>) {
+    while rx.changed().await.is_ok() {
+        let snapshot = rx.borrow_and_update().clone();
+        if let Some(Ok(devices)) = snapshot {
+            DP::on_devices_changed(&devices).await;
+        }
+    }
+}
+
+/// Add `mount` unless its container path is already mounted, warning if it
+/// was with a different source.
+fn add_mount(mounts: &mut Vec<Mount>, mount: Mount) {
+    match mounts
+        .iter()
+        .find(|x| x.container_path == mount.container_path)
+    {
+        Some(prev) if *prev != mount => warn!(
+            "conflicting mount on {}: keeping {prev:?}, ignoring {mount:?}",
+            mount.container_path
+        ),
+        Some(_) => {}
+        None => mounts.push(mount),
+    }
+}
+
+/// Whether two device lists advertise the same devices: same ids with the
+/// same health and NUMA nodes, whatever the order of devices or nodes.
+fn same_devices(a: &[Device], b: &[Device]) -> bool {
+    // sorted rather than collected into a map, duplicate ids must count
+    fn key(devices: &[Device]) -> Vec<(&String, &String, Vec<i64>)> {
+        let mut key: Vec<_> = devices
+            .iter()
+            .map(|device| {
+                let mut nodes: Vec<_> = device
+                    .topology
+                    .iter()
+                    .flat_map(|x| x.nodes.iter().map(|node| node.id))
+                    .collect();
+                nodes.sort_unstable();
+                (&device.id, &device.health, nodes)
+            })
+            .collect();
+        key.sort_unstable();
+        key
+    }
+    a.len() == b.len() && key(a) == key(b)
+}
+
+/// Apply the discovery `filter`, then `with_device_health`.
+async fn with_checks<DP: GenericDevicePlugin>(
+    devices: Vec<Device>,
+    filter: &Option<DeviceFilter>,
+) -> Vec<Device> {
+    let devices = match filter {
+        Some(filter) => filter.apply(devices),
+        None => devices,
+    };
+    with_device_health::<DP>(devices).await
+}
+
+/// Replace the health of `devices` with `DP::device_health`, running at most
+/// `DP::DEVICE_HEALTH_CHECKS` checks at once.
+async fn with_device_health<DP: GenericDevicePlugin>(mut devices: Vec<Device>) -> Vec<Device> {
+    let Some(concurrency) = DP::DEVICE_HEALTH_CHECKS else {
+        return devices;
+    };
+    let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+    let mut checks = JoinSet::new();
+    for (i, device) in devices.iter().cloned().enumerate() {
+        let semaphore = semaphore.clone();
+        checks.spawn(async move {
+            let _permit = semaphore.acquire_owned().await;
+            (i, DP::device_health(&device).await)
+        });
+    }
+    while let Some(res) = checks.join_next().await {
+        match res {
+            Ok((i, health)) => devices[i].health = health,
+            Err(e) => error!("device health check failed: {e}"),
+        }
+    }
+    devices
+}
+
+/// Apply asynchronous health updates onto polled devices.
+fn with_health_overrides(
+    mut devices: Vec<Device>,
+    health_overrides: &HashMap<String, String>,
+) -> Vec<Device> {
+    for device in &mut devices {
+        if let Some(health) = health_overrides.get(&device.id) {
+            device.health.clone_from(health);
+        }
+    }
+    devices
+}
+
+/// Append devices that disappeared within `DP::DEVICE_DISAPPEARANCE_GRACE` as
+/// unhealthy, `last_seen` tracks when each device was last returned.
+fn with_disappeared<DP: GenericDevicePlugin>(
+    mut devices: Vec<Device>,
+    last_seen: &mut HashMap<String, (Device, Instant)>,
+) -> Vec<Device> {
+    if DP::DEVICE_DISAPPEARANCE_GRACE.is_zero() {
+        return devices;
+    }
+
+    let now = Instant::now();
+    for device in &devices {
+        last_seen.insert(device.id.clone(), (device.clone(), now));
+    }
+    last_seen.retain(|_, (_, seen)| now.duration_since(*seen) < DP::DEVICE_DISAPPEARANCE_GRACE);
+
+    let present: HashSet<_> = devices.iter().map(|x| x.id.clone()).collect();
+    let mut disappeared: Vec<_> = last_seen
+        .values()
+        .filter(|(device, _)| !present.contains(&device.id))
+        .map(|(device, _)| Device {
+            health: UNHEALTHY.to_string(),
+            ..device.clone()
+        })
+        .collect();
+    // keep the list stable across polls so it isn't resent for nothing
+    disappeared.sort_by(|a, b| a.id.cmp(&b.id));
+    devices.extend(disappeared);
+    devices
+}
+
+/// Substitute `DP::empty_device_list` for an empty device list, tracking the
+/// last non-empty list and since when discovery is empty.
+fn with_empty_device_list<DP: GenericDevicePlugin>(
+    devices: Vec<Device>,
+    last_devices: &mut Vec<Device>,
+    empty_since: &mut Option<Instant>,
+) -> Vec<Device> {
+    if !devices.is_empty() {
+        *empty_since = None;
+        last_devices.clone_from(&devices);
+        return devices;
+    }
+    let since = *empty_since.get_or_insert_with(Instant::now);
+    DP::empty_device_list(last_devices, since.elapsed())
+}
+
+/// Drop negative NUMA node ids (e.g. a `-1` "no NUMA node" sentinel), which
+/// would confuse kubelet's topology manager. A device left without nodes gets
+/// no topology.
+fn with_valid_topology(devices: Vec<Device>) -> Vec<Device> {
+    devices
+        .into_iter()
+        .map(|mut device| {
+            if let Some(topology) = &mut device.topology {
+                let before = topology.nodes.len();
+                topology.nodes.retain(NumaNode::is_valid);
+                if topology.nodes.len() != before {
+                    warn!(
+                        "device {} has invalid NUMA node ids, dropping them",
+                        device.id
+                    );
+                }
+                if topology.nodes.is_empty() {
+                    device.topology = None;
+                }
+            }
+            device
+        })
+        .collect()
+}
+
+/// Downgrade the permissions of `spec` to `allowed`, failing if none is left.
+fn clamp_permissions(spec: &mut DeviceSpec, allowed: DevicePermissions) -> Result<(), Status> {
+    let requested: DevicePermissions = spec.permissions.parse().map_err(|e: Status| {
+        Status::internal(format!("device {}: {}", spec.host_path, e.message()))
+    })?;
+    let granted = requested & allowed;
+    if granted.is_empty() {
+        return Err(Status::internal(format!(
+            "device {} requested permissions {requested}, none of which is allowed",
+            spec.host_path
+        )));
+    }
+    if granted != requested {
+        warn!(
+            "device {} requested permissions {requested}, downgraded to {granted}",
+            spec.host_path
+        );
+        spec.permissions = granted.to_string();
+    }
+    Ok(())
+}
+
+/// Replace internal device ids with public ones, recording the mapping in
+/// `device_ids`.
+fn with_public_ids<DP: GenericDevicePlugin>(
+    devices: Vec<Device>,
+    device_ids: &mut HashMap<String, String>,
+) -> Vec<Device> {
+    device_ids.reserve(devices.len());
+    devices
+        .into_iter()
+        .map(|device| {
+            let id = DP::public_device_id(&device.id);
+            device_ids.insert(id.clone(), device.id);
+            Device { id, ..device }
+        })
+        .collect()
+}
+
+/// latest discovery result, `None` until the first poll finished
+type DeviceSnapshot = Option<Result<Vec<Device>, Status>>;
+type DeviceSnapshotSender = watch::Sender<DeviceSnapshot>;
+
+type ListAndWatchItem = Result<ListAndWatchResponse, Status>;
+
+/// sending half of a ListAndWatch stream
+enum DeviceListSender {
+    /// every device list is delivered, waiting for kubelet if necessary
+    Lossless(mpsc::Sender<ListAndWatchItem>),
+    /// only the latest device list is kept, stale ones are overwritten
+    KeepLatest(watch::Sender<Option<ListAndWatchItem>>),
+}
+
+impl DeviceListSender {
+    /// Completes when the stream has been dropped by kubelet.
+    async fn closed(&self) {
+        match self {
+            Self::Lossless(tx) => tx.closed().await,
+            Self::KeepLatest(tx) => tx.closed().await,
+        }
+    }
+
+    async fn send(&self, item: ListAndWatchItem) -> Result<(), SendError<()>> {
+        match self {
+            Self::Lossless(tx) => tx.send(item).await.map_err(|_| SendError(())),
+            Self::KeepLatest(tx) => tx.send(Some(item)).map_err(|_| SendError(())),
+        }
+    }
 }
 
 #[async_trait::async_trait]
-impl<DP: GenericDevicePlugin> DevicePlugin for DP {
+impl<DP: GenericDevicePlugin> DevicePlugin for GenericDevicePluginService<DP> {
     /// GetDevicePluginOptions returns options to be communicated with Device
     /// Manager
     async fn get_device_plugin_options(
         &self,
         _request: Request<Empty>,
     ) -> Result<Response<DevicePluginOptions>, Status> {
-        let resp = DevicePluginOptions {
-            pre_start_required: DP::PRE_START_REQUIRED,
-            get_preferred_allocation_available: DP::GET_PREFERRED_ALLOCATION_AVAILABLE,
-        };
+        let resp = DP::device_plugin_options();
+        if !self.options_logged.swap(true, Ordering::Relaxed) {
+            info!(
+                "{}: advertised options: pre_start_required={}, \
+                 get_preferred_allocation_available={}",
+                DP::RESOURCE_NAME,
+                resp.pre_start_required,
+                resp.get_preferred_allocation_available
+            );
+        }
         return Ok(Response::new(resp));
     }
 
@@ -55,38 +1035,57 @@ impl<DP: GenericDevicePlugin> DevicePlugin for DP {
         &self,
         _request: Request<Empty>,
     ) -> Result<Response<Self::ListAndWatchStream>, Status> {
-        let (tx, rx) = mpsc::channel(128);
+        let Some(guard) = self.state.start_stream(self.config.max_streams) else {
+            warn!("too many ListAndWatch streams, rejecting a new one");
+            return Err(Status::resource_exhausted(
+                "too many concurrent ListAndWatch streams",
+            ));
+        };
+        let (tx, stream) = if self.config.keep_latest {
+            let (tx, rx) = watch::channel(None);
+            let stream: Self::ListAndWatchStream =
+                Box::pin(WatchStream::from_changes(rx).filter_map(|x| x));
+            (DeviceListSender::KeepLatest(tx), stream)
+        } else {
+            let (tx, rx) = mpsc::channel(128);
+            let stream: Self::ListAndWatchStream = Box::pin(ReceiverStream::new(rx));
+            (DeviceListSender::Lossless(tx), stream)
+        };
+        let mut rx = self.subscribe_devices();
+        let policy = self.config.stream_error_policy;
         tokio::spawn(async move {
-            let mut prev_devices = Err(Status::unknown(""));
+            let _guard = guard;
             loop {
-                if tx.is_closed() {
-                    break;
-                }
-
-                let devices_resp = DP::get_devices().await;
-
-                // if error or changed
-                if devices_resp.is_err() || devices_resp.as_ref().ok() != prev_devices.as_ref().ok()
-                {
-                    prev_devices = devices_resp.clone();
-                    match tx
-                        .send(devices_resp.map(|x| ListAndWatchResponse { devices: x }))
-                        .await
-                    {
-                        Ok(()) => match &prev_devices {
-                            Ok(pd) => info!("found {} devices, new device list sent!", pd.len()),
-                            Err(e) => error!("failed to get devices: {e}"),
-                        },
+                let snapshot = rx.borrow_and_update().clone();
+                if let Some(devices_resp) = snapshot.and_then(|x| with_error_policy(x, policy)) {
+                    let resp = devices_resp
+                        .map(|x| ListAndWatchResponse { devices: x })
+                        .and_then(check_message_size);
+                    let len = resp.as_ref().map(|x| x.devices.len()).ok();
+                    match tx.send(resp).await {
+                        Ok(()) => {
+                            if let Some(len) = len {
+                                info!("found {len} devices, new device list sent!")
+                            }
+                        }
                         Err(e) => {
                             error!("failed to send new device list: {e}");
                             break;
                         }
                     }
                 }
-                sleep(DP::DEVICE_POLL_INTERVAL).await;
+
+                tokio::select! {
+                    _ = tx.closed() => break,
+                    changed = rx.changed() => {
+                        if changed.is_err() {
+                            break;
+                        }
+                    }
+                }
             }
         });
-        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+        Ok(Response::new(stream))
     }
 
     /// GetPreferredAllocation returns a preferred set of devices to allocate
@@ -98,17 +1097,26 @@ impl<DP: GenericDevicePlugin> DevicePlugin for DP {
         &self,
         request: Request<PreferredAllocationRequest>,
     ) -> Result<Response<PreferredAllocationResponse>, Status> {
+        let deadline = self.deadline(request.metadata());
         let request = request.into_inner();
         let mut container_responses = Vec::with_capacity(request.container_requests.len());
         for req in request.container_requests {
-            container_responses.push(
+            let resp = with_deadline(
+                deadline,
                 DP::get_container_preferred_allocation(
-                    req.available_device_i_ds,
-                    req.must_include_device_i_ds,
+                    self.internal_device_ids(req.available_device_i_ds),
+                    self.internal_device_ids(req.must_include_device_i_ds.clone()),
                     req.allocation_size,
-                )
-                .await?,
+                ),
+            )
+            .await?;
+            check_groups::<DP>(resp.ids(), &self.state).map_err(Status::internal)?;
+            let resp = ContainerPreferredAllocationResponse::from_ids(
+                resp.ids().iter().map(|id| DP::public_device_id(id)),
             );
+            resp.check_must_include(&req.must_include_device_i_ds)?;
+            resp.check_allocation_size(req.allocation_size)?;
+            container_responses.push(resp);
         }
         return Ok(Response::new(PreferredAllocationResponse {
             container_responses,
@@ -122,11 +1130,45 @@ impl<DP: GenericDevicePlugin> DevicePlugin for DP {
         &self,
         request: Request<AllocateRequest>,
     ) -> Result<Response<AllocateResponse>, Status> {
+        if let Some(limiter) = &self.config.allocate_rate_limit {
+            if let Err(wait) = limiter.lock().unwrap().try_acquire() {
+                return Err(Status::resource_exhausted(format!(
+                    "allocate rate limit exceeded, retry in {wait:?}"
+                )));
+            }
+        }
+        let deadline = self.deadline(request.metadata());
         let request = request.into_inner();
-        let mut container_responses = Vec::with_capacity(request.container_requests.len());
-        for req in request.container_requests {
-            container_responses.push(DP::container_allocate(req.devices_ids).await?);
+        // devices are exclusive, even between containers of the same pod
+        let mut requested = HashSet::new();
+        let duplicates: HashSet<_> = request
+            .container_requests
+            .iter()
+            .flat_map(|req| &req.devices_ids)
+            .filter(|id| !requested.insert(*id))
+            .collect();
+        if !duplicates.is_empty() {
+            return Err(Status::invalid_argument(format!(
+                "devices {duplicates:?} requested more than once"
+            )));
         }
+        let reserved: Vec<_> = if self.config.exclusive_allocation {
+            let ids: Vec<String> = request
+                .container_requests
+                .iter()
+                .flat_map(|req| req.devices_ids.iter().cloned())
+                .collect();
+            self.state.reserve(&ids).map_err(|reserved| {
+                Status::failed_precondition(format!("devices {reserved:?} already allocated"))
+            })?;
+            ids
+        } else {
+            vec![]
+        };
+        let container_responses = self
+            .allocate_containers(request.container_requests, deadline)
+            .await
+            .inspect_err(|_| self.state.release(&reserved))?;
         return Ok(Response::new(AllocateResponse {
             container_responses,
         }));
@@ -139,7 +1181,204 @@ impl<DP: GenericDevicePlugin> DevicePlugin for DP {
         &self,
         request: Request<PreStartContainerRequest>,
     ) -> Result<Response<PreStartContainerResponse>, Status> {
-        DP::pre_start_container(request.into_inner().devices_ids).await?;
+        let deadline = self.deadline(request.metadata());
+        let device_ids = self.internal_device_ids(request.into_inner().devices_ids);
+        with_deadline(deadline, DP::pre_start_container(device_ids)).await?;
         return Ok(Response::new(PreStartContainerResponse {}));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tests::{TestPlugin, DEVICES},
+        HEALTHY,
+    };
+
+    type Service = GenericDevicePluginService<TestPlugin>;
+
+    fn device(id: &str) -> Device {
+        Device {
+            id: id.to_string(),
+            health: HEALTHY.to_string(),
+            topology: None,
+        }
+    }
+
+    #[test]
+    fn duplicate_ids() {
+        let (a, b) = (device("a"), device("b"));
+        assert!(same_devices(
+            &[a.clone(), b.clone()],
+            &[b.clone(), a.clone()]
+        ));
+        assert!(!same_devices(
+            &[a.clone(), a.clone(), b.clone()],
+            &[a.clone(), b.clone(), b.clone()]
+        ));
+        assert!(!same_devices(&[a.clone(), a.clone()], &[a]));
+    }
+
+    #[test]
+    fn permuted_list_sent_once() {
+        let state = Arc::new(PluginState::default());
+        let (tx, mut rx) = watch::channel(None);
+        let mut prev_devices = Err(Status::unknown(""));
+
+        let devices = vec![device("a"), device("b"), device("c")];
+        Service::publish(&tx, &state, &mut prev_devices, Ok(devices));
+        assert!(rx.has_changed().unwrap());
+        rx.borrow_and_update();
+
+        let devices = vec![device("c"), device("a"), device("b")];
+        Service::publish(&tx, &state, &mut prev_devices, Ok(devices));
+        assert!(!rx.has_changed().unwrap());
+
+        let devices = vec![device("c"), device("a")];
+        Service::publish(&tx, &state, &mut prev_devices, Ok(devices));
+        assert!(rx.has_changed().unwrap());
+    }
+
+    #[tokio::test]
+    async fn sorted_device_list() {
+        DEVICES.set(vec![device("c"), device("a"), device("d"), device("b")]);
+        let service = Service::new(ServiceConfig::default(), Arc::default());
+
+        let mut stream = service
+            .list_and_watch(Request::new(Empty {}))
+            .await
+            .unwrap()
+            .into_inner();
+        let resp = stream.next().await.unwrap().unwrap();
+        let ids: Vec<_> = resp.devices.iter().map(|x| x.id.as_str()).collect();
+        assert_eq!(ids, ["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn drained_keeps_internal_ids() {
+        let state = Arc::new(PluginState::default());
+        let (tx, _rx) = watch::channel(None);
+        let mut prev_devices = Err(Status::unknown(""));
+        state.set_drained(true);
+
+        let devices = vec![device("internal-a")];
+        Service::publish(&tx, &state, &mut prev_devices, Ok(devices));
+        assert!(state.devices().is_empty());
+        assert!(!state.is_advertised("public-a"));
+        assert_eq!(state.internal_device_id("public-a"), "internal-a");
+    }
+
+    #[tokio::test]
+    async fn partial_lists_skip_state() {
+        let state = PluginState::default();
+        let (tx, rx) = watch::channel(None);
+        let ids = [
+            "internal-e",
+            "internal-d",
+            "internal-c",
+            "internal-b",
+            "internal-a",
+        ];
+        let stream: DeviceStream = Box::pin(tokio_stream::iter(ids.map(|id| Ok(device(id)))));
+
+        let devices = Service::collect_devices(stream, &tx, &state, &None, &HashMap::new(), true)
+            .await
+            .unwrap();
+        assert_eq!(devices.len(), 5);
+        // sent after 2 then 4 devices
+        let partial = rx.borrow().clone().unwrap().unwrap();
+        let partial: Vec<_> = partial.iter().map(|x| x.id.as_str()).collect();
+        assert_eq!(partial, ["public-b", "public-c", "public-d", "public-e"]);
+        assert!(state.is_advertised("public-b"));
+        assert_eq!(state.internal_device_id("public-b"), "internal-b");
+        assert!(state.cached_device("internal-b").is_none());
+        assert!(state.devices().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn discovery_stops_with_last_stream() {
+        DEVICES.set(vec![device("a")]);
+        let service = Service::new(ServiceConfig::default(), Arc::default());
+
+        let mut stream = service
+            .list_and_watch(Request::new(Empty {}))
+            .await
+            .unwrap()
+            .into_inner();
+        stream.next().await.unwrap().unwrap();
+        assert!(service.discovery.lock().unwrap().upgrade().is_some());
+
+        drop(stream);
+        sleep(TestPlugin::DEVICE_POLL_INTERVAL * 2).await;
+        // the sender, and with it the notifier, is gone
+        assert!(service.discovery.lock().unwrap().upgrade().is_none());
+    }
+
+    fn allocate_request(containers: &[&[&str]]) -> Request<AllocateRequest> {
+        Request::new(AllocateRequest {
+            container_requests: containers
+                .iter()
+                .map(|ids| ContainerAllocateRequest {
+                    devices_ids: ids.iter().map(|x| x.to_string()).collect(),
+                })
+                .collect(),
+        })
+    }
+
+    #[tokio::test]
+    async fn cooldown_after_success_only() {
+        let config = ServiceConfig {
+            allocate_cooldown: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+        let service = Service::new(config, Arc::default());
+
+        // the whole request fails, no device is cooling down
+        let e = service
+            .allocate(allocate_request(&[&["a"], &["broken"]]))
+            .await
+            .unwrap_err();
+        assert_eq!(e.code(), tonic::Code::Internal);
+        service.allocate(allocate_request(&[&["a"]])).await.unwrap();
+        let e = service
+            .allocate(allocate_request(&[&["a"]]))
+            .await
+            .unwrap_err();
+        assert_eq!(e.code(), tonic::Code::Unavailable);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn allocate_rate_limit() {
+        let config = ServiceConfig {
+            // a token every 500ms, 2 at once
+            allocate_rate_limit: Some(Arc::new(Mutex::new(TokenBucket::new(2.0, 2)))),
+            ..Default::default()
+        };
+        let service = Service::new(config, Arc::default());
+
+        for id in ["a", "b"] {
+            service.allocate(allocate_request(&[&[id]])).await.unwrap();
+        }
+        let e = service
+            .allocate(allocate_request(&[&["c"]]))
+            .await
+            .unwrap_err();
+        assert_eq!(e.code(), tonic::Code::ResourceExhausted);
+
+        tokio::time::advance(Duration::from_millis(400)).await;
+        let e = service
+            .allocate(allocate_request(&[&["c"]]))
+            .await
+            .unwrap_err();
+        assert_eq!(e.code(), tonic::Code::ResourceExhausted);
+
+        tokio::time::advance(Duration::from_millis(100)).await;
+        service.allocate(allocate_request(&[&["c"]])).await.unwrap();
+        let e = service
+            .allocate(allocate_request(&[&["d"]]))
+            .await
+            .unwrap_err();
+        assert_eq!(e.code(), tonic::Code::ResourceExhausted);
+    }
+}
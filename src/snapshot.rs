@@ -0,0 +1,167 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use prost::Message;
+use tokio::{task::spawn_blocking, time::Instant};
+use tracing::warn;
+
+use super::{pb::Device, state::PluginState};
+
+/// bumped on incompatible changes, older snapshots are ignored
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// State persisted across restarts to `GenericDevicePluginServer::state_file`.
+#[derive(Clone, PartialEq, Message)]
+struct SnapshotProto {
+    #[prost(uint32, tag = "1")]
+    version: u32,
+    #[prost(message, repeated, tag = "2")]
+    devices: Vec<Device>,
+    /// public device id -> unix time of its last allocation, in milliseconds
+    #[prost(map = "string, uint64", tag = "3")]
+    last_allocated: HashMap<String, u64>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Snapshot {
+    pub(crate) devices: Vec<Device>,
+    pub(crate) last_allocated: HashMap<String, Instant>,
+}
+
+impl Snapshot {
+    /// Load a snapshot, a missing, corrupt or outdated file gives nothing.
+    pub(crate) fn load(path: &Path) -> Option<Self> {
+        let buf = match fs::read(path) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("failed to read state file {path:?}: {e}");
+                return None;
+            }
+        };
+        let proto = match SnapshotProto::decode(buf.as_slice()) {
+            Ok(proto) => proto,
+            Err(e) => {
+                warn!("ignoring corrupt state file {path:?}: {e}");
+                return None;
+            }
+        };
+        if proto.version != SNAPSHOT_VERSION {
+            warn!(
+                "ignoring state file {path:?} of version {}, expected {SNAPSHOT_VERSION}",
+                proto.version
+            );
+            return None;
+        }
+
+        let (now, sys_now) = (Instant::now(), unix_millis(SystemTime::now()));
+        let last_allocated = proto
+            .last_allocated
+            .into_iter()
+            .filter_map(|(id, at)| {
+                // allocations "in the future" (clock changes) count as now
+                let elapsed = Duration::from_millis(sys_now.saturating_sub(at));
+                Some((id, now.checked_sub(elapsed)?))
+            })
+            .collect();
+        Some(Self {
+            devices: proto.devices,
+            last_allocated,
+        })
+    }
+
+    /// Write the snapshot to a temporary file renamed over `path`, so a crash
+    /// never leaves a truncated file behind.
+    pub(crate) fn save(&self, path: &Path) -> io::Result<()> {
+        let (now, sys_now) = (Instant::now(), SystemTime::now());
+        let proto = SnapshotProto {
+            version: SNAPSHOT_VERSION,
+            devices: self.devices.clone(),
+            last_allocated: self
+                .last_allocated
+                .iter()
+                .map(|(id, at)| {
+                    let at = sys_now.checked_sub(now - *at).unwrap_or(UNIX_EPOCH);
+                    (id.clone(), unix_millis(at))
+                })
+                .collect(),
+        };
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, proto.encode_to_vec())?;
+        fs::rename(&tmp, path)
+    }
+}
+
+/// Rewrite the state file whenever `state` changes, on a blocking thread so
+/// allocate never waits for the disk. Changes made during a write are
+/// coalesced into the next one.
+pub(crate) async fn sync(state: Arc<PluginState>) {
+    let mut requests = state.subscribe_persist();
+    while requests.changed().await.is_ok() {
+        let Some((path, snapshot)) = state.snapshot() else {
+            continue;
+        };
+        let saved = spawn_blocking(move || snapshot.save(&path).map_err(|e| (path, e))).await;
+        if let Ok(Err((path, e))) = saved {
+            warn!("failed to persist state to {path:?}: {e}");
+        }
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|x| x.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HEALTHY;
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state");
+        let at = Instant::now() - Duration::from_secs(5);
+        let snapshot = Snapshot {
+            devices: vec![Device {
+                id: "a".to_string(),
+                health: HEALTHY.to_string(),
+                topology: None,
+            }],
+            last_allocated: HashMap::from([("a".to_string(), at)]),
+        };
+        snapshot.save(&path).unwrap();
+        assert!(!path.with_extension("tmp").exists());
+
+        let loaded = Snapshot::load(&path).unwrap();
+        assert_eq!(loaded.devices, snapshot.devices);
+        let loaded_at = loaded.last_allocated["a"];
+        // stored in milliseconds of wall-clock time
+        let drift = loaded_at.max(at) - loaded_at.min(at);
+        assert!(drift < Duration::from_millis(100), "{drift:?}");
+    }
+
+    #[test]
+    fn unusable_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state");
+        assert!(Snapshot::load(&path).is_none());
+
+        fs::write(&path, b"\xff\xff\xff").unwrap();
+        assert!(Snapshot::load(&path).is_none());
+
+        let outdated = SnapshotProto {
+            version: SNAPSHOT_VERSION + 1,
+            ..Default::default()
+        };
+        fs::write(&path, outdated.encode_to_vec()).unwrap();
+        assert!(Snapshot::load(&path).is_none());
+    }
+}
@@ -0,0 +1,693 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    future::pending,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    sync::{broadcast, watch},
+    time::Instant,
+};
+use tonic::Status;
+use tracing::{info, warn};
+
+use super::{pb::Device, snapshot::Snapshot};
+
+/// how many lifecycle events are kept for introspection
+const MAX_EVENTS: usize = 32;
+/// how many device diffs a slow `subscribe_changes` receiver can lag behind
+const MAX_DIFFS: usize = 64;
+
+/// Lifecycle phase of a `GenericDevicePluginServer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerPhase {
+    Starting,
+    Serving,
+    Registered,
+    Restarting,
+}
+
+impl ServerPhase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Starting => "starting",
+            Self::Serving => "serving",
+            Self::Registered => "registered",
+            Self::Restarting => "restarting",
+        }
+    }
+}
+
+/// A lifecycle event, timestamped in seconds since unix epoch.
+#[derive(Debug, Clone)]
+pub struct ServerEvent {
+    pub timestamp: u64,
+    pub message: String,
+}
+
+/// Summary of observed call durations.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencySummary {
+    pub count: u64,
+    pub min: Duration,
+    pub max: Duration,
+    pub total: Duration,
+}
+
+impl LatencySummary {
+    pub fn mean(&self) -> Duration {
+        match u32::try_from(self.count) {
+            Ok(0) => Duration::ZERO,
+            Ok(count) => self.total / count,
+            Err(_) => self.total.div_f64(self.count as f64),
+        }
+    }
+
+    fn observe(&mut self, elapsed: Duration) {
+        self.min = match self.count {
+            0 => elapsed,
+            _ => self.min.min(elapsed),
+        };
+        self.max = self.max.max(elapsed);
+        self.total += elapsed;
+        self.count += 1;
+    }
+}
+
+/// Change between two device lists advertised to kubelet, see
+/// `GenericDevicePluginHandle::subscribe_changes`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceDiff {
+    pub added: Vec<Device>,
+    pub removed: Vec<Device>,
+    /// devices whose health or topology changed, as they are now
+    pub changed: Vec<Device>,
+}
+
+impl DeviceDiff {
+    fn new(prev: &[Device], next: &[Device]) -> Self {
+        // indexed by id, lists can hold 100k+ devices
+        fn by_id(devices: &[Device]) -> HashMap<&str, &Device> {
+            devices.iter().map(|x| (x.id.as_str(), x)).collect()
+        }
+        let (prev_by_id, next_by_id) = (by_id(prev), by_id(next));
+        let mut diff = Self::default();
+        for device in next {
+            match prev_by_id.get(device.id.as_str()) {
+                None => diff.added.push(device.clone()),
+                Some(x) if *x != device => diff.changed.push(device.clone()),
+                Some(_) => {}
+            }
+        }
+        diff.removed = prev
+            .iter()
+            .filter(|x| !next_by_id.contains_key(x.id.as_str()))
+            .cloned()
+            .collect();
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Which build of the plugin is running.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionInfo {
+    /// version of this crate
+    pub crate_version: &'static str,
+    /// build metadata set with `GenericDevicePluginServer::build_info`
+    pub build: Option<String>,
+}
+
+/// Snapshot of the plugin metrics.
+#[derive(Debug, Clone, Default)]
+pub struct PluginMetrics {
+    /// time spent in `GenericDevicePlugin::container_allocate`, per container
+    pub container_allocate: LatencySummary,
+    /// errors reported by the socket watcher, each also recorded as an event
+    pub watcher_errors: u64,
+}
+
+/// State shared between the server loop, the device plugin service and
+/// introspection.
+pub(crate) struct PluginState {
+    phase: Mutex<ServerPhase>,
+    registered: AtomicBool,
+    restarts: AtomicU64,
+    devices: RwLock<Vec<Device>>,
+    /// ids of `devices`
+    advertised_ids: RwLock<HashSet<String>>,
+    /// public device id -> internal device id
+    device_ids: RwLock<HashMap<String, String>>,
+    /// internal device id -> device as returned by discovery
+    cached_devices: RwLock<HashMap<String, Device>>,
+    /// set once discovery returned a first device list
+    discovered: watch::Sender<bool>,
+    events: Mutex<VecDeque<ServerEvent>>,
+    metrics: Mutex<PluginMetrics>,
+    discovery_fatal: watch::Sender<Option<Status>>,
+    drained: watch::Sender<bool>,
+    /// notified to poll devices right away
+    rescan: watch::Sender<()>,
+    changes: broadcast::Sender<DeviceDiff>,
+    /// public device id -> when it was last allocated, with a cooldown
+    last_allocated: Mutex<HashMap<String, Instant>>,
+    /// public ids of devices allocated exclusively, until they disappear
+    reservations: Mutex<HashSet<String>>,
+    /// ListAndWatch forwarding tasks currently alive
+    active_streams: AtomicUsize,
+    build_info: RwLock<Option<String>>,
+    /// where devices and allocations are persisted across restarts
+    state_file: RwLock<Option<PathBuf>>,
+    /// notified when the state file is out of date, see `snapshot::sync`
+    persist: watch::Sender<()>,
+}
+
+impl Default for PluginState {
+    fn default() -> Self {
+        Self {
+            phase: Mutex::new(ServerPhase::Starting),
+            registered: AtomicBool::new(false),
+            restarts: AtomicU64::new(0),
+            devices: RwLock::new(vec![]),
+            advertised_ids: RwLock::default(),
+            device_ids: RwLock::default(),
+            cached_devices: RwLock::default(),
+            discovered: watch::channel(false).0,
+            events: Mutex::new(VecDeque::with_capacity(MAX_EVENTS)),
+            metrics: Mutex::default(),
+            discovery_fatal: watch::channel(None).0,
+            drained: watch::channel(false).0,
+            rescan: watch::channel(()).0,
+            changes: broadcast::channel(MAX_DIFFS).0,
+            last_allocated: Mutex::default(),
+            reservations: Mutex::default(),
+            active_streams: AtomicUsize::new(0),
+            build_info: RwLock::default(),
+            state_file: RwLock::default(),
+            persist: watch::channel(()).0,
+        }
+    }
+}
+
+impl PluginState {
+    pub(crate) fn phase(&self) -> ServerPhase {
+        *self.phase.lock().unwrap()
+    }
+
+    pub(crate) fn set_phase(&self, phase: ServerPhase) {
+        *self.phase.lock().unwrap() = phase;
+        self.registered
+            .store(phase == ServerPhase::Registered, Ordering::Relaxed);
+    }
+
+    pub(crate) fn registered(&self) -> bool {
+        self.registered.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn restarts(&self) -> u64 {
+        self.restarts.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn inc_restarts(&self) {
+        self.restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Last device list advertised to kubelet.
+    pub(crate) fn devices(&self) -> Vec<Device> {
+        self.devices.read().unwrap().clone()
+    }
+
+    pub(crate) fn set_devices(&self, devices: Vec<Device>) {
+        let diff = DeviceDiff::new(&self.devices.read().unwrap(), &devices);
+        *self.advertised_ids.write().unwrap() = devices.iter().map(|x| x.id.clone()).collect();
+        *self.devices.write().unwrap() = devices;
+        if diff.is_empty() {
+            return;
+        }
+        self.persist();
+        if self.changes.receiver_count() == 0 {
+            return;
+        }
+        if self.changes.len() == MAX_DIFFS {
+            warn!("device change subscriber lagging, dropping its oldest device diff");
+        }
+        let _ = self.changes.send(diff);
+    }
+
+    pub(crate) fn subscribe_changes(&self) -> broadcast::Receiver<DeviceDiff> {
+        self.changes.subscribe()
+    }
+
+    pub(crate) fn set_device_ids(&self, device_ids: HashMap<String, String>) {
+        *self.device_ids.write().unwrap() = device_ids;
+    }
+
+    /// Record the ids of a partially advertised list on top of the last
+    /// complete one, which `set_devices` replaces.
+    pub(crate) fn add_advertised_ids(&self, device_ids: HashMap<String, String>) {
+        let mut advertised_ids = self.advertised_ids.write().unwrap();
+        advertised_ids.extend(device_ids.keys().cloned());
+        self.device_ids.write().unwrap().extend(device_ids);
+    }
+
+    pub(crate) fn set_cached_devices(&self, devices: &[Device]) {
+        *self.cached_devices.write().unwrap() = devices
+            .iter()
+            .map(|device| (device.id.clone(), device.clone()))
+            .collect();
+        self.discovered.send_replace(true);
+    }
+
+    /// Wait for discovery to return a first device list.
+    pub(crate) async fn discovered(&self) {
+        let _ = self.discovered.subscribe().wait_for(|x| *x).await;
+    }
+
+    pub(crate) fn cached_device_ids(&self) -> Vec<String> {
+        self.cached_devices
+            .read()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn cached_device(&self, internal_id: &str) -> Option<Device> {
+        self.cached_devices
+            .read()
+            .unwrap()
+            .get(internal_id)
+            .cloned()
+    }
+
+    /// Internal id of an advertised device, unknown ids are kept as is.
+    pub(crate) fn internal_device_id(&self, public_id: &str) -> String {
+        self.device_ids
+            .read()
+            .unwrap()
+            .get(public_id)
+            .cloned()
+            .unwrap_or_else(|| public_id.to_string())
+    }
+
+    pub(crate) fn is_advertised(&self, public_id: &str) -> bool {
+        self.advertised_ids.read().unwrap().contains(public_id)
+    }
+
+    pub(crate) fn events(&self) -> Vec<ServerEvent> {
+        self.events.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub(crate) fn metrics(&self) -> PluginMetrics {
+        self.metrics.lock().unwrap().clone()
+    }
+
+    pub(crate) fn observe_container_allocate(&self, elapsed: Duration) {
+        self.metrics
+            .lock()
+            .unwrap()
+            .container_allocate
+            .observe(elapsed);
+    }
+
+    pub(crate) fn record_watcher_error(&self, e: &notify::Error) {
+        self.metrics.lock().unwrap().watcher_errors += 1;
+        self.push_event(format!("socket watcher error: {e}"));
+    }
+
+    /// Record an allocation of `ids` unless one of them was allocated less
+    /// than `cooldown` ago, returning how long to wait then. The allocation is
+    /// forgotten when the guard drops, unless it's kept once it succeeded.
+    pub(crate) fn start_cooldown(
+        &self,
+        ids: &[String],
+        cooldown: Duration,
+    ) -> Result<CooldownGuard<'_>, Duration> {
+        let now = Instant::now();
+        let mut last_allocated = self.last_allocated.lock().unwrap();
+        last_allocated.retain(|_, at| now.duration_since(*at) < cooldown);
+        let wait = ids
+            .iter()
+            .filter_map(|id| last_allocated.get(id))
+            .map(|at| cooldown - now.duration_since(*at))
+            .max();
+        if let Some(wait) = wait {
+            return Err(wait);
+        }
+        last_allocated.extend(ids.iter().map(|id| (id.clone(), now)));
+        Ok(CooldownGuard {
+            state: self,
+            ids: ids.to_vec(),
+            at: now,
+        })
+    }
+
+    /// Reserve all of `ids` at once, or none if some are already reserved,
+    /// returning them then.
+    pub(crate) fn reserve(&self, ids: &[String]) -> Result<(), Vec<String>> {
+        let mut reservations = self.reservations.lock().unwrap();
+        let reserved: Vec<_> = ids
+            .iter()
+            .filter(|id| reservations.contains(*id))
+            .cloned()
+            .collect();
+        if !reserved.is_empty() {
+            return Err(reserved);
+        }
+        reservations.extend(ids.iter().cloned());
+        Ok(())
+    }
+
+    pub(crate) fn release(&self, ids: &[String]) {
+        let mut reservations = self.reservations.lock().unwrap();
+        for id in ids {
+            reservations.remove(id);
+        }
+    }
+
+    pub(crate) fn has_reservations(&self) -> bool {
+        !self.reservations.lock().unwrap().is_empty()
+    }
+
+    /// Drop the reservations of devices no longer discovered.
+    pub(crate) fn retain_reservations(&self, present: &HashSet<String>) {
+        self.reservations
+            .lock()
+            .unwrap()
+            .retain(|id| present.contains(id));
+    }
+
+    pub(crate) fn reservations(&self) -> Vec<String> {
+        let mut reservations: Vec<_> = self.reservations.lock().unwrap().iter().cloned().collect();
+        reservations.sort_unstable();
+        reservations
+    }
+
+    /// Count a new ListAndWatch stream until the guard is dropped, unless
+    /// `max` streams are already active.
+    pub(crate) fn start_stream(self: &Arc<Self>, max: Option<usize>) -> Option<StreamGuard> {
+        self.active_streams
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                (!matches!(max, Some(max) if n >= max)).then_some(n + 1)
+            })
+            .ok()?;
+        Some(StreamGuard(self.clone()))
+    }
+
+    pub(crate) fn active_streams(&self) -> usize {
+        self.active_streams.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn version(&self) -> VersionInfo {
+        VersionInfo {
+            crate_version: env!("CARGO_PKG_VERSION"),
+            build: self.build_info.read().unwrap().clone(),
+        }
+    }
+
+    pub(crate) fn set_build_info(&self, build: String) {
+        *self.build_info.write().unwrap() = Some(build);
+    }
+
+    /// Persist state to `path` from now on, restoring what it holds from a
+    /// previous run.
+    pub(crate) fn set_state_file(&self, path: PathBuf) {
+        if let Some(snapshot) = Snapshot::load(&path) {
+            info!(
+                "restored {} devices and {} allocations from {path:?}",
+                snapshot.devices.len(),
+                snapshot.last_allocated.len()
+            );
+            *self.advertised_ids.write().unwrap() =
+                snapshot.devices.iter().map(|x| x.id.clone()).collect();
+            *self.devices.write().unwrap() = snapshot.devices;
+            *self.last_allocated.lock().unwrap() = snapshot.last_allocated;
+        }
+        *self.state_file.write().unwrap() = Some(path);
+    }
+
+    pub(crate) fn has_state_file(&self) -> bool {
+        self.state_file.read().unwrap().is_some()
+    }
+
+    /// Have the state file rewritten in the background.
+    fn persist(&self) {
+        self.persist.send_replace(());
+    }
+
+    pub(crate) fn subscribe_persist(&self) -> watch::Receiver<()> {
+        self.persist.subscribe()
+    }
+
+    /// Current state, to be written to the state file if any.
+    pub(crate) fn snapshot(&self) -> Option<(PathBuf, Snapshot)> {
+        let path = self.state_file.read().unwrap().clone()?;
+        let snapshot = Snapshot {
+            devices: self.devices(),
+            last_allocated: self.last_allocated.lock().unwrap().clone(),
+        };
+        Some((path, snapshot))
+    }
+
+    pub(crate) fn set_discovery_fatal(&self, status: Status) {
+        self.discovery_fatal.send_replace(Some(status));
+    }
+
+    /// Completes once discovery failed fatally.
+    pub(crate) async fn discovery_fatal(&self) -> Status {
+        let mut rx = self.discovery_fatal.subscribe();
+        let status = rx
+            .wait_for(Option::is_some)
+            .await
+            .ok()
+            .and_then(|x| x.clone());
+        match status {
+            Some(status) => status,
+            None => pending().await,
+        }
+    }
+
+    pub(crate) fn drained(&self) -> bool {
+        *self.drained.borrow()
+    }
+
+    pub(crate) fn set_drained(&self, drained: bool) {
+        if self
+            .drained
+            .send_if_modified(|x| std::mem::replace(x, drained) != drained)
+        {
+            let message = if drained {
+                "resource drained, advertising no devices"
+            } else {
+                "resource undrained, advertising discovered devices"
+            };
+            info!("{message}");
+            self.push_event(message);
+        }
+    }
+
+    pub(crate) fn subscribe_drained(&self) -> watch::Receiver<bool> {
+        self.drained.subscribe()
+    }
+
+    pub(crate) fn request_rescan(&self) {
+        self.rescan.send_replace(());
+    }
+
+    pub(crate) fn subscribe_rescan(&self) -> watch::Receiver<()> {
+        self.rescan.subscribe()
+    }
+
+    pub(crate) fn push_event(&self, message: impl Into<String>) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or_default();
+        let mut events = self.events.lock().unwrap();
+        if events.len() == MAX_EVENTS {
+            events.pop_front();
+        }
+        events.push_back(ServerEvent {
+            timestamp,
+            message: message.into(),
+        });
+    }
+}
+
+/// An active ListAndWatch stream, see `PluginState::start_stream`.
+pub(crate) struct StreamGuard(Arc<PluginState>);
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.0.active_streams.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// An allocation recorded by `PluginState::start_cooldown`.
+pub(crate) struct CooldownGuard<'a> {
+    state: &'a PluginState,
+    ids: Vec<String>,
+    at: Instant,
+}
+
+impl CooldownGuard<'_> {
+    /// Keep the allocation once it succeeded.
+    pub(crate) fn keep(mut self) {
+        self.ids.clear();
+        self.state.persist();
+    }
+}
+
+impl Drop for CooldownGuard<'_> {
+    fn drop(&mut self) {
+        if self.ids.is_empty() {
+            return;
+        }
+        let mut last_allocated = self.state.last_allocated.lock().unwrap();
+        for id in &self.ids {
+            // unless allocated again since, after the cooldown
+            if last_allocated.get(id) == Some(&self.at) {
+                last_allocated.remove(id);
+            }
+        }
+    }
+}
+
+/// Cheap, cloneable view on a running `GenericDevicePluginServer`, obtained
+/// with `GenericDevicePluginServer::handle` before calling `run`.
+#[derive(Clone)]
+pub struct GenericDevicePluginHandle {
+    pub(crate) state: Arc<PluginState>,
+}
+
+impl GenericDevicePluginHandle {
+    /// Current lifecycle phase.
+    pub fn phase(&self) -> ServerPhase {
+        self.state.phase()
+    }
+
+    /// Whether the plugin is currently registered to kubelet.
+    pub fn registered(&self) -> bool {
+        self.state.registered()
+    }
+
+    /// How many times the server restarted because of a socket change.
+    pub fn restarts(&self) -> u64 {
+        self.state.restarts()
+    }
+
+    /// Device list last advertised to kubelet, without probing the hardware
+    /// again: public ids (see `public_device_id`), disappeared devices still
+    /// in their grace period as unhealthy, and no devices while drained. See
+    /// `cached_device` for what discovery returned.
+    pub fn current_devices(&self) -> Vec<Device> {
+        self.state.devices()
+    }
+
+    /// Device with this id in the last list returned by `get_devices`, as
+    /// returned (internal id, before `public_device_id`), refreshed on every
+    /// poll. Meant for `container_allocate` to get at discovery metadata
+    /// without probing again: keep the handle in a `static`
+    /// `once_cell::sync::OnceCell` set before calling `run`.
+    pub fn cached_device(&self, id: &str) -> Option<Device> {
+        self.state.cached_device(id)
+    }
+
+    /// Stream of changes to the advertised device list, for observers outside
+    /// the plugin (dashboards, sidecars...). A receiver falling more than 64
+    /// diffs behind gets `RecvError::Lagged` and misses the oldest ones,
+    /// `current_devices` gives the full list to resync.
+    pub fn subscribe_changes(&self) -> broadcast::Receiver<DeviceDiff> {
+        self.state.subscribe_changes()
+    }
+
+    /// Whether a device id is part of the last device list advertised to
+    /// kubelet. May lag the hardware by up to one `DEVICE_POLL_INTERVAL`.
+    pub fn is_advertised(&self, id: &str) -> bool {
+        self.state.is_advertised(id)
+    }
+
+    /// Recent lifecycle events, oldest first.
+    pub fn events(&self) -> Vec<ServerEvent> {
+        self.state.events()
+    }
+
+    /// Advertise no devices while drained, regardless of discovery, e.g. to
+    /// cordon the resource during maintenance without stopping the plugin.
+    /// Running containers keep their devices.
+    pub fn set_drained(&self, drained: bool) {
+        self.state.set_drained(drained)
+    }
+
+    /// Whether the resource is currently drained, see `set_drained`.
+    pub fn drained(&self) -> bool {
+        self.state.drained()
+    }
+
+    /// Poll devices right away instead of waiting for the rest of
+    /// `DEVICE_POLL_INTERVAL`, e.g. after a driver reload.
+    pub fn rescan(&self) {
+        self.state.request_rescan();
+    }
+
+    /// Devices reserved by `exclusive_allocation`, sorted.
+    pub fn reservations(&self) -> Vec<String> {
+        self.state.reservations()
+    }
+
+    /// Release reservations early, e.g. once the plugin knows the pods using
+    /// these devices are gone.
+    pub fn release(&self, ids: &[String]) {
+        self.state.release(ids)
+    }
+
+    /// How many ListAndWatch streams are currently being served.
+    pub fn active_streams(&self) -> usize {
+        self.state.active_streams()
+    }
+
+    /// Crate version and build metadata of the running plugin.
+    pub fn version(&self) -> VersionInfo {
+        self.state.version()
+    }
+
+    /// Snapshot of the plugin metrics.
+    pub fn metrics(&self) -> PluginMetrics {
+        self.state.metrics()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HEALTHY;
+
+    #[test]
+    fn restored_devices_are_advertised() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state");
+        let snapshot = Snapshot {
+            devices: vec![Device {
+                id: "a".to_string(),
+                health: HEALTHY.to_string(),
+                topology: None,
+            }],
+            last_allocated: HashMap::new(),
+        };
+        snapshot.save(&path).unwrap();
+
+        let state = PluginState::default();
+        state.set_state_file(path);
+        assert_eq!(state.devices(), snapshot.devices);
+        assert!(state.is_advertised("a"));
+        assert!(!state.is_advertised("b"));
+    }
+}
@@ -0,0 +1,86 @@
+use std::{fmt::Write, net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    spawn,
+    time::timeout,
+};
+use tracing::{info, warn};
+
+use super::{json::quote, state::PluginState};
+
+/// how long a client may take to send its request before being dropped
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serve the plugin state as JSON on every request to `addr`.
+pub(crate) async fn serve(addr: SocketAddr, state: Arc<PluginState>) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("status server started on {addr}!");
+
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("failed to accept status connection: {e}");
+                continue;
+            }
+        };
+        let body = render(&state);
+        spawn(async move {
+            // the request itself doesn't matter, every path serves the status
+            let mut buf = [0; 1024];
+            if timeout(READ_TIMEOUT, stream.read(&mut buf)).await.is_err() {
+                return;
+            }
+            let resp = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: \
+                 {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            if let Err(e) = stream.write_all(resp.as_bytes()).await {
+                warn!("failed to write status response: {e}");
+            }
+        });
+    }
+}
+
+fn render(state: &PluginState) -> String {
+    let mut out = String::new();
+    let version = state.version();
+    let _ = write!(
+        out,
+        "{{\"version\":{},\"build\":{},\"phase\":\"{}\",\"registered\":{},\"restarts\":{},\
+         \"devices\":[",
+        quote(version.crate_version),
+        version.build.as_deref().map_or("null".to_string(), quote),
+        state.phase().as_str(),
+        state.registered(),
+        state.restarts()
+    );
+    for (i, device) in state.devices().iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(
+            out,
+            "{{\"id\":{},\"health\":{}}}",
+            quote(&device.id),
+            quote(&device.health)
+        );
+    }
+    out.push_str("],\"events\":[");
+    for (i, event) in state.events().iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(
+            out,
+            "{{\"timestamp\":{},\"message\":{}}}",
+            event.timestamp,
+            quote(&event.message)
+        );
+    }
+    out.push_str("]}");
+    out
+}
@@ -0,0 +1,325 @@
+use std::{
+    fmt::{self, Write},
+    ops::{BitAnd, BitOr, BitOrAssign},
+    str::FromStr,
+};
+
+use tonic::Status;
+use tracing::warn;
+
+use super::pb::{
+    ContainerAllocateResponse, ContainerPreferredAllocationResponse, DeviceSpec, Mount, NumaNode,
+    TopologyInfo,
+};
+
+/// `Device.health` of a device that can be allocated.
+pub static HEALTHY: &str = "Healthy";
+/// `Device.health` of a device that must not be allocated.
+pub static UNHEALTHY: &str = "Unhealthy";
+
+/// cgroup device permissions of a `DeviceSpec`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DevicePermissions(u8);
+
+impl DevicePermissions {
+    pub const READ: Self = Self(1);
+    pub const WRITE: Self = Self(1 << 1);
+    pub const MKNOD: Self = Self(1 << 2);
+
+    pub fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl BitOr for DevicePermissions {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitAnd for DevicePermissions {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+impl BitOrAssign for DevicePermissions {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+/// Renders the kubelet representation, e.g. `rw` or `rwm`.
+impl fmt::Display for DevicePermissions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (perm, c) in [(Self::READ, 'r'), (Self::WRITE, 'w'), (Self::MKNOD, 'm')] {
+            if self.contains(perm) {
+                f.write_char(c)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<DevicePermissions> for String {
+    fn from(perms: DevicePermissions) -> Self {
+        perms.to_string()
+    }
+}
+
+/// Parses a non-empty combination of `r` (read), `w` (write) and `m` (mknod).
+impl FromStr for DevicePermissions {
+    type Err = Status;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            Status::invalid_argument(format!(
+                "invalid device permissions {s:?}, expected a combination of r, w and m"
+            ))
+        };
+        let mut perms = Self(0);
+        for c in s.chars() {
+            perms |= match c {
+                'r' => Self::READ,
+                'w' => Self::WRITE,
+                'm' => Self::MKNOD,
+                _ => return Err(invalid()),
+            };
+        }
+        if perms.is_empty() {
+            return Err(invalid());
+        }
+        Ok(perms)
+    }
+}
+
+impl DeviceSpec {
+    /// Build a device spec exposing `host_path` at `container_path`.
+    pub fn new(
+        host_path: impl Into<String>,
+        container_path: impl Into<String>,
+        permissions: DevicePermissions,
+    ) -> Self {
+        Self {
+            container_path: container_path.into(),
+            host_path: host_path.into(),
+            permissions: permissions.into(),
+        }
+    }
+}
+
+/// `DeviceSpec` pattern expanded for every allocated device, where `{id}` is
+/// the device id and `{index}` its position in the allocation request, e.g.
+/// `DeviceSpecTemplate::new("/dev/{id}", "/dev/accel{index}", perms)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceSpecTemplate {
+    host_path: String,
+    container_path: String,
+    permissions: DevicePermissions,
+}
+
+impl DeviceSpecTemplate {
+    /// Fails unless both paths are absolute and only use the `{id}` and
+    /// `{index}` placeholders.
+    pub fn new(
+        host_path: impl Into<String>,
+        container_path: impl Into<String>,
+        permissions: DevicePermissions,
+    ) -> Result<Self, Status> {
+        let template = Self {
+            host_path: host_path.into(),
+            container_path: container_path.into(),
+            permissions,
+        };
+        for path in [&template.host_path, &template.container_path] {
+            if !path.starts_with('/') {
+                return Err(Status::invalid_argument(format!(
+                    "device path template {path:?} isn't absolute"
+                )));
+            }
+            expand_template(path, 0, "")?;
+        }
+        Ok(template)
+    }
+
+    /// Spec of the `index`th device of an allocation. Ids that could escape
+    /// the templated directory (empty, `.`, `..` or containing `/`) are
+    /// rejected.
+    pub fn expand(&self, index: usize, id: &str) -> Result<DeviceSpec, Status> {
+        if id.is_empty() || id == "." || id == ".." || id.contains('/') {
+            return Err(Status::invalid_argument(format!(
+                "device id {id:?} can't be used in a path"
+            )));
+        }
+        Ok(DeviceSpec::new(
+            expand_template(&self.host_path, index, id)?,
+            expand_template(&self.container_path, index, id)?,
+            self.permissions,
+        ))
+    }
+}
+
+/// Substitute the placeholders of a device path template in a single pass, so
+/// ids containing braces are inserted verbatim.
+fn expand_template(template: &str, index: usize, id: &str) -> Result<String, Status> {
+    let invalid = |reason: &str| {
+        Status::invalid_argument(format!(
+            "invalid device path template {template:?}: {reason}"
+        ))
+    };
+    let mut out = String::with_capacity(template.len() + id.len());
+    let mut rest = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        out.push_str(&rest[..start]);
+        if rest[start..].starts_with('}') {
+            return Err(invalid("unmatched '}'"));
+        }
+        let Some(len) = rest[start..].find('}') else {
+            return Err(invalid("unmatched '{'"));
+        };
+        match &rest[start + 1..start + len] {
+            "id" => out.push_str(id),
+            "index" => {
+                let _ = write!(out, "{index}");
+            }
+            name => return Err(invalid(&format!("unknown placeholder {{{name}}}"))),
+        }
+        rest = &rest[start + len + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+impl Mount {
+    /// Build a read-write mount of `host_path` at `container_path`.
+    ///
+    /// v1beta1 `Mount` has no propagation field: the runtime always mounts it
+    /// private, so host mounts made after the container started aren't seen.
+    pub fn new(host_path: impl Into<String>, container_path: impl Into<String>) -> Self {
+        Self {
+            container_path: container_path.into(),
+            host_path: host_path.into(),
+            read_only: false,
+        }
+    }
+
+    /// Build a read-only mount of `host_path` at `container_path`.
+    pub fn read_only(host_path: impl Into<String>, container_path: impl Into<String>) -> Self {
+        Self {
+            read_only: true,
+            ..Self::new(host_path, container_path)
+        }
+    }
+}
+
+impl NumaNode {
+    /// Build a NUMA node from its id, non-negative by construction.
+    pub fn new(id: u32) -> Self {
+        Self { id: id.into() }
+    }
+
+    /// Build a NUMA node from a raw id, e.g. read from sysfs `numa_node`.
+    /// Negative ids (`-1` meaning "no NUMA node") give `None`, as kubelet
+    /// expects non-negative ids.
+    pub fn checked(id: i64) -> Option<Self> {
+        u32::try_from(id).ok().map(Self::new)
+    }
+
+    /// Whether the id can be advertised to kubelet.
+    pub fn is_valid(&self) -> bool {
+        u32::try_from(self.id).is_ok()
+    }
+}
+
+impl TopologyInfo {
+    /// Topology of a device attached to a single NUMA node.
+    pub fn numa_node(id: u32) -> Self {
+        Self {
+            nodes: vec![NumaNode::new(id)],
+        }
+    }
+}
+
+impl ContainerAllocateResponse {
+    /// Combine two responses: envs and annotations are unioned (`other` wins
+    /// on conflict, with a warning), mounts, devices and CDI devices are
+    /// concatenated.
+    pub fn merge(mut self, other: Self) -> Self {
+        for (key, value) in other.envs {
+            if let Some(prev) = self.envs.insert(key.clone(), value.clone()) {
+                if prev != value {
+                    warn!("conflicting env {key}: {prev:?} overridden by {value:?}");
+                }
+            }
+        }
+        for (key, value) in other.annotations {
+            if let Some(prev) = self.annotations.insert(key.clone(), value.clone()) {
+                if prev != value {
+                    warn!("conflicting annotation {key}: {prev:?} overridden by {value:?}");
+                }
+            }
+        }
+        self.mounts.extend(other.mounts);
+        self.devices.extend(other.devices);
+        self.cdi_devices.extend(other.cdi_devices);
+        self
+    }
+}
+
+impl ContainerPreferredAllocationResponse {
+    /// Build a preferred allocation from device ids.
+    pub fn from_ids(ids: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            device_i_ds: ids.into_iter().collect(),
+        }
+    }
+
+    /// Build a preferred allocation from device ids, making sure exactly
+    /// `allocation_size` devices are preferred.
+    pub fn from_ids_sized(
+        ids: impl IntoIterator<Item = String>,
+        allocation_size: i32,
+    ) -> Result<Self, Status> {
+        let resp = Self::from_ids(ids);
+        resp.check_allocation_size(allocation_size)?;
+        Ok(resp)
+    }
+
+    /// Preferred device ids.
+    pub fn ids(&self) -> &[String] {
+        &self.device_i_ds
+    }
+
+    /// Check that exactly `allocation_size` devices are preferred.
+    pub fn check_allocation_size(&self, allocation_size: i32) -> Result<(), Status> {
+        if usize::try_from(allocation_size).ok() != Some(self.device_i_ds.len()) {
+            return Err(Status::internal(format!(
+                "preferred allocation has {} devices, but {allocation_size} were requested",
+                self.device_i_ds.len()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Check that every must-include device is preferred.
+    pub fn check_must_include(&self, must_include_ids: &[String]) -> Result<(), Status> {
+        let missing: Vec<_> = must_include_ids
+            .iter()
+            .filter(|id| !self.device_i_ds.contains(id))
+            .collect();
+        if !missing.is_empty() {
+            return Err(Status::internal(format!(
+                "preferred allocation is missing must-include devices: {missing:?}"
+            )));
+        }
+        Ok(())
+    }
+}